@@ -0,0 +1,169 @@
+// Benchmarks of the decryption methods and of the stecker searches, run with
+//   cargo run --release --example bench [steckers [TRIALS]]
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+use enigma::{cryptanalysis, encrypt, ngram};
+use enigma::error::Error;
+
+static ENGLISH_QUADGRAMS : &str = include_str!("../data/english_quadgrams.txt");
+
+// The f64 log-probabilities of the english quadgrams, as the original table held them,
+// with the floor used by NGramModel for the missing ones.
+fn f64_log_probs() -> Vec<f64> {
+    let counts: Vec<(usize, f64)> = ENGLISH_QUADGRAMS.lines().filter_map(|line| {
+        let (ngram, count) = line.split_once(' ')?;
+        let index = encrypt::input_to_u8(ngram).iter().fold(0, |acc, &c| acc * 26 + c as usize);
+        Some((index, count.trim().parse().ok()?))
+    }).collect();
+    let total: f64 = counts.iter().map(|&(_, count)| count).sum();
+    let mut log_probs = vec![(0.001 / total).log10(); 26 * 26 * 26 * 26];
+    for &(index, count) in counts.iter() {
+        log_probs[index] = (count / total).log10();
+    }
+    log_probs
+}
+
+// Compares the time taken to score all the start positions of a wheel order using
+// encrypt_u8 and a table of f64 log-probabilities, as was done originally, with the table
+// driven decryption and the compact n-gram table used by the search.
+fn bench_decryption() -> Result<(), Error> {
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let model = ngram::Language::English.ngrams(4).unwrap();
+    let plaintext = encrypt::input_to_u8("THEQUICKBROWNFOXJUMPSOVERTHELAZYDOGANDTHENITRANSAWAYINTOTHEFORESTWHEREITWASNEVERSEENAGAIN");
+    let rotor_config = vec![ 0u8, 1, 2 ];
+    let rings = vec![ 0u8, 0, 0 ];
+    let ciphertext = encrypt::encrypt_u8(&world, &plaintext, &rotor_config, &vec![ 10u8, 3, 23 ], &rings)?;
+    let key = encrypt::Key::new(rotor_config.clone(), vec![ 0u8, 0, 0 ], rings.clone());
+    let keys: Vec<Vec<u8>> = (0..26 * 26 * 26).map(|i|
+        vec![ (i / 676) as u8, (i / 26 % 26) as u8, (i % 26) as u8 ]).collect();
+
+    let log_probs = f64_log_probs();
+    let original_score = |text: &Vec<u8>| {
+        let mut score = 0.;
+        let mut index = 0;
+        for (idx, &c) in text.iter().enumerate() {
+            index = (index % (26 * 26 * 26)) * 26 + c as usize;
+            if 3 <= idx { score += log_probs[index]; }
+        }
+        score
+    };
+    let reference_run = || keys.iter().map(|key|
+        original_score(&encrypt::encrypt_u8(&world, &ciphertext, &rotor_config, key, &rings).unwrap())).collect::<Vec<f64>>();
+    let (reference, reference_time) = best_time(reference_run);
+    // The 16-bit scores are within 0.0002 of the f64 ones, so the sums only differ by the
+    // accumulated rounding.
+    let tolerance = 0.0002 * ciphertext.len() as f64;
+    let same = |scores: &Vec<f64>| reference.iter().zip(scores.iter()).all(|(a, b)| (a - b).abs() < tolerance);
+
+    let table_driven_run = || {
+        let scramblers = encrypt::scramblers(&tables, &key).unwrap();
+        keys.iter().map(|key| model.score_iter(scramblers.encrypt_iter(&ciphertext, key))).collect::<Vec<f64>>()
+    };
+    let (table_driven, table_driven_time) = best_time(table_driven_run);
+    assert!(same(&table_driven));
+
+    let batched_run = || {
+        let scramblers = encrypt::scramblers(&tables, &key).unwrap();
+        let mut batched = Vec::new();
+        for chunk in keys.chunks(encrypt::LANES) {
+            if chunk.len() == encrypt::LANES {
+                let positions: [usize; encrypt::LANES] = std::array::from_fn(|lane| encrypt::key_index(&chunk[lane]));
+                batched.extend(model.score_lanes(scramblers.encrypt_lanes(&ciphertext, positions)).iter());
+            }
+            else {
+                batched.extend(chunk.iter().map(|key| model.score_iter(scramblers.encrypt_iter(&ciphertext, key))));
+            }
+        }
+        batched
+    };
+    let (batched, batched_time) = best_time(batched_run);
+    assert!(same(&batched));
+
+    let per_candidate = |d: Duration| d.as_secs_f64() * 1e9 / keys.len() as f64;
+    println!("{} candidates, {} letters", keys.len(), ciphertext.len());
+    println!("encrypt_u8:   {:.1}ns/candidate", per_candidate(reference_time));
+    println!("table driven: {:.1}ns/candidate, {:.1}x", per_candidate(table_driven_time),
+             reference_time.as_secs_f64() / table_driven_time.as_secs_f64());
+    println!("{} lanes:     {:.1}ns/candidate, {:.1}x", encrypt::LANES, per_candidate(batched_time),
+             reference_time.as_secs_f64() / batched_time.as_secs_f64());
+    Ok(())
+}
+
+// Runs f a few times, returning its result and the shortest time taken, which is the
+// least disturbed by the rest of the system.
+fn best_time<T, F: Fn() -> T>(f: F) -> (T, Duration) {
+    let mut best = None;
+    for _ in 0..3 {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        if best.as_ref().is_none_or(|&(_, time)| elapsed < time) {
+            best = Some((result, elapsed));
+        }
+    }
+    best.unwrap()
+}
+
+static CORPUS : &str = include_str!("../data/german_corpus.txt");
+
+// Success rate of the stecker searches when the rest of the key is known, for random keys
+// with 10 stecker pairs and messages of increasing length. The messages are taken from
+// the corpus the german model was trained on so the rates are optimistic, the point being
+// to compare the optimisers.
+fn bench_steckers(trials: usize) -> Result<(), Error> {
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let model = ngram::Language::German.ngrams(4).unwrap();
+    let corpus = ngram::normalise(CORPUS, true, true);
+    let optimisers = [ cryptanalysis::Optimiser::HillClimb, cryptanalysis::Optimiser::Annealing,
+                       cryptanalysis::Optimiser::RandomRestart, cryptanalysis::Optimiser::Tabu ];
+    let spec = cryptanalysis::SearchSpec::new();
+    let mut rng = cryptanalysis::Rng::new(0);
+    print!("letters");
+    for optimiser in optimisers.iter() { print!(" {:>16}", optimiser.name()); }
+    println!();
+    for &len in [ 30usize, 50, 75, 100, 150, 200 ].iter() {
+        let mut successes = vec![0; optimisers.len()];
+        let mut times = vec![Duration::new(0, 0); optimisers.len()];
+        for trial in 0..trials {
+            let key = spec.random_key(10, &mut rng)?;
+            let start = rng.below(corpus.len() - len);
+            let ciphertext = encrypt::encrypt_key(&world, &key, &corpus[start..start + len])?;
+            let unplugged = encrypt::Key { plugboard: encrypt::identity_plugboard(), .. key.clone() };
+            for (idx, &optimiser) in optimisers.iter().enumerate() {
+                let start = Instant::now();
+                let mut optimiser_rng = cryptanalysis::Rng::new(trial as u64);
+                let (_, found) = cryptanalysis::recover_steckers(&tables, &model, &ciphertext, &unplugged, optimiser, &mut optimiser_rng)?;
+                times[idx] += start.elapsed();
+                if found == key { successes[idx] += 1; }
+            }
+        }
+        print!("{:7}", len);
+        for (&successes, time) in successes.iter().zip(times.iter()) {
+            print!(" {:5.1}% {:7.1}ms", 100. * successes as f64 / trials as f64,
+                   time.as_secs_f64() * 1e3 / trials as f64);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let res = match args[..] {
+        [] => bench_decryption(),
+        ["steckers"] => bench_steckers(20),
+        ["steckers", trials] => match trials.parse() {
+            Ok(trials) if 0 < trials => bench_steckers(trials),
+            _ => Err(Error::Usage(format!("invalid number of trials '{}'", trials))),
+        },
+        _ => Err(Error::Usage("usage: bench [steckers [TRIALS]]".to_string())),
+    };
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        process::exit(err.exit_code());
+    }
+}
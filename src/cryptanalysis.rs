@@ -1,27 +1,40 @@
-use std::collections::BTreeSet;
-use encrypt;
-use quadgram_data;
+use std::cmp;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::slice;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::encrypt;
+use crate::error::Error;
+use crate::ngram;
+use crate::ngram::NGramModel;
 
 struct Product {
     state: Vec<u8>,
     max_value: u8,
+    done: bool,
 }
 
 impl Product {
+    #[allow(clippy::needless_return, clippy::redundant_field_names)]
     fn new(max_value: u8, n: usize) -> Product {
-        let mut state = Vec::new();
-        state.resize(n, 0u8);
-        return Product { max_value: max_value, state: state };
+        let state = vec![0; n];
+        // With no value to choose, only the empty product exists.
+        return Product { max_value: max_value, state: state, done: max_value == 0 && 0 < n };
     }
 }
 
 impl Iterator for Product {
     type Item = Vec<u8>;
 
+    #[allow(clippy::needless_return)]
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if self.done { return None; }
         let res = self.state.clone();
         let n = self.state.len();
-        for i in (0us .. n) {
+        for i in 0..n {
             let i = n - 1 - i;
             if self.state[i] != self.max_value - 1 {
                 self.state[i] += 1;
@@ -31,87 +44,1973 @@ impl Iterator for Product {
                 self.state[i] = 0u8;
             }
         }
-        return None;
+        self.done = true;
+        return Some(res);
     }
 }
 
-fn score(text : &Vec<u8>) -> f64 {
-    let mut score : f64 = 0.0;
-    let mut qgram_index : usize = 0;
-    for idx in (0us .. text.len()) {
-        let c = text[idx];
-        if 25 < c { continue; }
-        qgram_index = (qgram_index % (26 * 26 * 26)) * 26 + c as usize;
-        if 3 <= idx {
-            score += quadgram_data::QGRAM[qgram_index];
+// Describes the part of the key that is already known, the search only enumerating the
+// remaining freedom. As in encrypt::Key, rotors and rings start from the rightmost rotor.
+#[derive(Clone, Debug)]
+pub struct SearchSpec {
+    // The wheel order with None for the slots that have to be searched.
+    pub rotors: Vec<Option<u8>>,
+    // The rotors that can be used in the slots that are not pinned.
+    pub rotor_pool: Vec<u8>,
+    pub reflectors: Vec<u8>,
+    // The greek wheels to try for a four rotor machine, empty for three rotors.
+    pub greek: Vec<u8>,
+    // Rings and positions include the greek wheel when there is one.
+    pub rings: Option<Vec<u8>>,
+    pub positions: Option<Vec<u8>>,
+    // The known stecker pairs, as a permutation.
+    pub plugboard: Vec<u8>,
+    // Number of candidates kept from the position search for the ring search.
+    pub candidates: usize,
+    // Number of complete keys returned.
+    pub results: usize,
+    // How to search for the stecker pairs that are not known, None to only use the
+    // known ones.
+    pub steckers: Option<Optimiser>,
+    // Seed for the randomised stecker searches.
+    pub seed: u64,
+}
+
+impl Default for SearchSpec {
+    fn default() -> SearchSpec { SearchSpec::new() }
+}
+
+impl SearchSpec {
+    // The whole search space: all the wheel orders using rotors I to V, reflector B.
+    pub fn new() -> SearchSpec {
+        SearchSpec {
+            rotors: vec![None, None, None],
+            rotor_pool: (0..encrypt::ARMY_ROTORS).collect(),
+            reflectors: vec![1],
+            greek: Vec::new(),
+            rings: None,
+            positions: None,
+            plugboard: encrypt::identity_plugboard(),
+            candidates: 100,
+            results: 1,
+            steckers: None,
+            seed: 0,
+        }
+    }
+
+    // The naval M4: 336 wheel orders using rotors I to VIII, the thin reflectors and both
+    // greek wheels.
+    pub fn m4() -> SearchSpec {
+        SearchSpec {
+            rotor_pool: (0..encrypt::NAVAL_ROTORS).collect(),
+            reflectors: encrypt::THIN_REFLECTORS.to_vec(),
+            greek: encrypt::GREEK_ROTORS.to_vec(),
+            .. SearchSpec::new()
+        }
+    }
+
+    fn key_len(&self) -> usize {
+        if self.greek.is_empty() { 3 } else { 4 }
+    }
+
+    // Checks that every key of the search can be set on a machine, so that the searches
+    // return an error rather than panic on a malformed spec.
+    fn check(&self) -> Result<(), Error> {
+        let key_len = self.key_len();
+        if self.rotors.len() != 3 {
+            return Err(Error::Search(format!("the wheel order should have 3 slots, not {}", self.rotors.len())));
+        }
+        if self.rings.as_ref().is_some_and(|rings| rings.len() != key_len) ||
+            self.positions.as_ref().is_some_and(|positions| positions.len() != key_len) {
+            return Err(Error::Search(format!("the rings and positions should be given for {} rotors", key_len)));
+        }
+        if self.rings.iter().chain(self.positions.iter()).flatten().any(|&c| 26 <= c) {
+            return Err(Error::Search("the rings and positions should be letters".to_string()));
+        }
+        encrypt::check_rotors(&self.rotors.iter().filter_map(|&r| r).collect::<Vec<u8>>())?;
+        for &rotor in self.rotor_pool.iter().chain(self.greek.iter()) {
+            encrypt::check_rotors(&[rotor])?;
+        }
+        if let Some(&rotor) = self.rotors.iter().flatten().chain(self.rotor_pool.iter()).find(|r| encrypt::GREEK_ROTORS.contains(r)) {
+            return Err(Error::Search(format!("the greek wheel {} can only be used in the slot of the greek wheels", encrypt::rotor_name(rotor))));
+        }
+        if let Some(&rotor) = self.greek.iter().find(|r| !encrypt::GREEK_ROTORS.contains(r)) {
+            return Err(Error::Search(format!("{} is not a greek wheel", encrypt::rotor_name(rotor))));
+        }
+        if self.wheel_orders().is_empty() {
+            return Err(Error::Search("the pool does not have enough other rotors for the searched slots".to_string()));
+        }
+        if self.reflectors.is_empty() {
+            return Err(Error::Search("there should be a reflector to try".to_string()));
+        }
+        for &reflector in self.reflectors.iter() {
+            if encrypt::REFLECTOR_COUNT <= reflector {
+                return Err(Error::Search(format!("there is no reflector with index {}", reflector)));
+            }
+            if encrypt::THIN_REFLECTORS.contains(&reflector) != (key_len == 4) {
+                return Err(Error::Search(format!("reflector {} cannot be used with {} rotors", encrypt::reflector_name(reflector), key_len)));
+            }
+        }
+        let plugboard = &self.plugboard;
+        if plugboard.len() != 26 || (0..26).any(|c| 26 <= plugboard[c] || plugboard[plugboard[c] as usize] != c as u8) {
+            return Err(Error::Search("the known plugboard should consist of pairs of letters".to_string()));
+        }
+        Ok(())
+    }
+
+    // A random key as on a key sheet: a wheel order from the pool, a greek wheel if the
+    // spec has some, a reflector of the spec and the given number of stecker pairs. The
+    // rotors, rings, positions and plugboard of the spec are not used.
+    pub fn random_key(&self, pairs: usize, rng: &mut Rng) -> Result<encrypt::Key, Error> {
+        if self.rotor_pool.len() < 3 || self.reflectors.is_empty() || 13 < pairs {
+            return Err(Error::Search("a key takes 3 rotors of the pool, a reflector and at most 13 stecker pairs".to_string()));
+        }
+        let mut rotors = self.rotor_pool.clone();
+        rng.shuffle(&mut rotors);
+        rotors.truncate(3);
+        if !self.greek.is_empty() {
+            rotors.push(self.greek[rng.below(self.greek.len())]);
+        }
+        let letters = |rng: &mut Rng| (0..rotors.len()).map(|_| rng.below(26) as u8).collect::<Vec<u8>>();
+        let mut key = encrypt::Key::new(rotors.clone(), letters(rng), letters(rng));
+        key.reflector = self.reflectors[rng.below(self.reflectors.len())];
+        let mut steckered: Vec<u8> = (0..26).collect();
+        rng.shuffle(&mut steckered);
+        for pair in steckered.chunks(2).take(pairs) {
+            key.plugboard[pair[0] as usize] = pair[1];
+            key.plugboard[pair[1] as usize] = pair[0];
+        }
+        key.check()?;
+        Ok(key)
+    }
+
+    fn wheel_orders(&self) -> Vec<Vec<u8>> {
+        let free_slots = self.rotors.iter().filter(|r| r.is_none()).count();
+        Product::new(self.rotor_pool.len() as u8, free_slots).filter_map(|choice| {
+            let mut choice = choice.iter().map(|&idx| self.rotor_pool[idx as usize]);
+            let wheel_order: Vec<u8> = self.rotors.iter().map(|&r| r.or_else(|| choice.next()).unwrap()).collect();
+            let all_distinct = wheel_order.iter().enumerate().all(|(i, r)| !wheel_order[..i].contains(r));
+            if all_distinct { Some(wheel_order) } else { None }
+        }).collect()
+    }
+
+    // One key per wheel order and reflector, the unknown rings and positions being set to A.
+    // With a greek wheel there is also one key per greek wheel and offset, i.e. position
+    // minus ring, as the greek wheel never moves: its position varies unless it is known,
+    // in which case its ring varies unless it is known too. Beta with B-thin and Gamma with
+    // C-thin at offset A act as the thick B and C, but a search never mixes thick and thin
+    // reflectors so none of the templates duplicates another.
+    fn templates(&self) -> Vec<encrypt::Key> {
+        let key_len = self.key_len();
+        let rings = self.rings.clone().unwrap_or(vec![0u8; key_len]);
+        let positions = self.positions.clone().unwrap_or(vec![0u8; key_len]);
+        let greek_settings: Vec<(u8, u8)> = match (&self.rings, &self.positions) {
+            _ if self.greek.is_empty() => vec![(0, 0)],
+            (&Some(_), &Some(_)) => vec![(rings[3], positions[0])],
+            (_, &None) => (0..26).map(|position| (rings[3], position)).collect(),
+            (&None, &Some(_)) => (0..26).map(|ring| (ring, positions[0])).collect(),
+        };
+        let greek_wheels: Vec<Option<u8>> =
+            if self.greek.is_empty() { vec![None] } else { self.greek.iter().map(|&g| Some(g)).collect() };
+        let mut templates = Vec::new();
+        for wheel_order in self.wheel_orders().into_iter() {
+            for &reflector in self.reflectors.iter() {
+                for &greek in greek_wheels.iter() {
+                    for &(greek_ring, greek_position) in greek_settings.iter() {
+                        let mut key = encrypt::Key {
+                            rotors: wheel_order.clone(),
+                            reflector,
+                            rings: rings.clone(),
+                            positions: positions.clone(),
+                            plugboard: self.plugboard.clone(),
+                        };
+                        if let Some(greek) = greek {
+                            key.rotors.push(greek);
+                            key.rings[3] = greek_ring;
+                            key.positions[0] = greek_position;
+                        }
+                        templates.push(key);
+                    }
+                }
+            }
+        }
+        templates
+    }
+}
+
+type Candidate = (i64, encrypt::Key);
+
+// The key with the positions of the three rotors that move replaced, the greek wheel of
+// a four rotor key keeping its position.
+fn with_positions(key: &encrypt::Key, positions: &[u8]) -> encrypt::Key {
+    let mut key = key.clone();
+    let n = key.positions.len();
+    key.positions[n - 3..].copy_from_slice(positions);
+    key
+}
+
+fn get_worst<T: Ord + Clone>(treeset: &BTreeSet<T>) -> Option<T> {
+    treeset.iter().next().cloned()
+}
+
+// Keeps the max_len largest candidates. Ties on the score are broken by comparing
+// the keys so that the content of the set does not depend on the order in which the
+// candidates were inserted.
+fn insert_bounded<T: Ord + Clone>(treeset: &mut BTreeSet<T>, candidate: T, max_len: usize) {
+    if treeset.len() < max_len {
+        treeset.insert(candidate);
+    }
+    else {
+        match get_worst(treeset) {
+            Some(worst) if worst < candidate => {
+                treeset.remove(&worst);
+                treeset.insert(candidate);
+            },
+            _ => (),
         }
     }
-    return score;
 }
 
-fn get_worst(treeset: &BTreeSet<(i64, Vec<u8>, Vec<u8>)>) -> Option<(i64, Vec<u8>, Vec<u8>)> {
-    match treeset.iter().next().clone() {
-        None => None,
-        Some(v) => Some(v.clone()),
+// Calls f with the index of each start position and the corresponding score. The start
+// positions are processed encrypt::LANES at a time, the remaining ones one by one.
+fn score_positions<F: FnMut(usize, f64)>(scramblers: &encrypt::Scramblers, model: &NGramModel, ciphertext: &[u8], mut f: F) {
+    let mut index = 0;
+    while index + encrypt::LANES <= 26 * 26 * 26 {
+        let positions: [usize; encrypt::LANES] = std::array::from_fn(|lane| index + lane);
+        let scores = model.score_lanes(scramblers.encrypt_lanes(ciphertext, positions));
+        for (lane, &score) in scores.iter().enumerate() { f(index + lane, score); }
+        index += encrypt::LANES;
+    }
+    for index in index..26 * 26 * 26 {
+        f(index, model.score_iter(scramblers.encrypt_iter(ciphertext, &encrypt::index_key(index))));
     }
 }
 
-fn brute_force_rotors_and_key(world: &encrypt::World, ciphertext : &Vec<u8>, rings : &Vec<u8>) -> BTreeSet<(i64, Vec<u8>, Vec<u8>)> {
-    let mut best_rotors_and_key = BTreeSet::new();
-    // Quite awful and inefficient...
-    for rotor_config in Product::new(5u8, 3us) {
-        if rotor_config[0] == rotor_config[1] ||
-           rotor_config[0] == rotor_config[2] ||
-           rotor_config[1] == rotor_config[2] { continue; }
-        for key in Product::new(26u8, 3us) {
-            let plaintext = encrypt::encrypt_u8(world, ciphertext, &rotor_config, &key, rings);
-            let score = score(&plaintext);
-            let score = score as i64;
-            // Only keep the 100 best keys...
-            if best_rotors_and_key.len() < 100 {
-                best_rotors_and_key.insert((score, key, rotor_config.clone()));
+// Scores the settings of template where either the positions or the rings, or none of
+// them if both are known, vary.
+fn brute_force_key(tables: &encrypt::Tables, model: &NGramModel, ciphertext: &[u8], spec: &SearchSpec, template: &encrypt::Key, best_rotors_and_key: &mut BTreeSet<Candidate>) {
+    let max_len = spec.candidates;
+    let mut consider = |score: f64, key: &dyn Fn() -> encrypt::Key| {
+        let score = score as i64;
+        // Only keep the best keys, avoiding allocations for the ones that are discarded.
+        match best_rotors_and_key.iter().next() {
+            Some(&(worst_score, _)) if best_rotors_and_key.len() == max_len && score < worst_score => return,
+            _ => (),
+        }
+        insert_bounded(best_rotors_and_key, (score, key()), max_len);
+    };
+    match (&spec.positions, &spec.rings) {
+        (&None, _) => {
+            let scramblers = encrypt::scramblers_unchecked(tables, template);
+            score_positions(&scramblers, model, ciphertext, |index, score| consider(score, &|| with_positions(template, &encrypt::index_key(index))));
+        },
+        (&Some(_), &None) => {
+            let mut key = template.clone();
+            for index in 0..26 * 26 * 26 {
+                key.rings[..3].copy_from_slice(&encrypt::index_key(index));
+                let score = model.score_iter(encrypt::encrypt_iter_unchecked(tables, &key, ciphertext));
+                consider(score, &|| key.clone());
             }
-            else {
-                match get_worst(&best_rotors_and_key) {
-                    None => (),
-                    Some(worst) => {
-                        let (worst_score, _, _) = worst;
-                        if worst_score < score {
-                            best_rotors_and_key.remove(&worst);
-                            best_rotors_and_key.insert((score, key, rotor_config.clone()));
-                        }
+        },
+        (&Some(_), &Some(_)) => {
+            let score = model.score_iter(encrypt::encrypt_iter_unchecked(tables, template, ciphertext));
+            consider(score, &|| template.clone());
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    // Search for the wheel orders and start positions, one step per wheel order and
+    // reflector.
+    Wheels,
+    // Ring and stecker searches, one step per candidate kept from the previous stage.
+    Keys,
+}
+
+pub struct Progress {
+    pub stage: Stage,
+    pub done: usize,
+    pub total: usize,
+    // The best key so far, during the Wheels stage only its start positions are right.
+    pub best: Option<(f64, encrypt::Key)>,
+    // Time since the beginning of the stage.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    // Estimated time until the end of the stage.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done == 0 { return None; }
+        Some(self.elapsed.mul_f64((self.total - self.done) as f64 / self.done as f64))
+    }
+}
+
+// The state of a search, saved periodically so that an interrupted search can be resumed.
+// The stecker searches use a random generator seeded from the rank of the candidate, so
+// the completed wheel orders and candidates are enough to get the same result as an
+// uninterrupted search.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    // Identifies the ciphertext, model and spec, resuming requires the same ones.
+    search: u64,
+    // Whether each wheel order and reflector of the spec has been searched.
+    wheels: Vec<bool>,
+    // The best candidates over the wheel orders searched so far.
+    candidates: BTreeSet<Candidate>,
+    // The complete key obtained from each candidate, only recorded once all the wheel
+    // orders have been searched.
+    keys: Vec<Option<(f64, encrypt::Key)>>,
+    // The letters of the ciphertext and the options the search was started with, as given
+    // to with_options, so that shards can be merged without repeating them.
+    ciphertext: String,
+    options: Vec<(String, String)>,
+}
+
+static CHECKPOINT_HEADER : &str = "enigma-checkpoint 1";
+
+fn key_to_string(key: &encrypt::Key) -> String {
+    let numbers = |values: &Vec<u8>| values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",");
+    let plugboard: String = key.plugboard.iter().map(|&c| encrypt::chr(c)).collect();
+    format!("{} {} {} {} {}", key.reflector, numbers(&key.rotors), numbers(&key.rings), numbers(&key.positions), plugboard)
+}
+
+fn key_from_fields(fields: &[&str]) -> Option<encrypt::Key> {
+    let numbers = |field: &str| field.split(',').map(|v| v.parse().ok()).collect::<Option<Vec<u8>>>();
+    if fields.len() != 5 { return None; }
+    let plugboard = encrypt::input_to_u8(fields[4]);
+    if plugboard.len() != 26 { return None; }
+    Some(encrypt::Key {
+        reflector: fields[0].parse().ok()?,
+        rotors: numbers(fields[1])?,
+        rings: numbers(fields[2])?,
+        positions: numbers(fields[3])?,
+        plugboard,
+    })
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", CHECKPOINT_HEADER)?;
+        writeln!(f, "search {:016x}", self.search)?;
+        if !self.ciphertext.is_empty() {
+            writeln!(f, "ciphertext {}", self.ciphertext)?;
+        }
+        for (option, value) in self.options.iter() {
+            writeln!(f, "option {} {}", option, value)?;
+        }
+        let wheels: String = self.wheels.iter().map(|&done| if done { '1' } else { '0' }).collect();
+        writeln!(f, "wheels {}", wheels)?;
+        for &(score, ref key) in self.candidates.iter().rev() {
+            writeln!(f, "candidate {} {}", score, key_to_string(key))?;
+        }
+        for (idx, complete) in self.keys.iter().enumerate() {
+            match *complete {
+                None => writeln!(f, "pending {}", idx)?,
+                Some((score, ref key)) => writeln!(f, "key {} {} {}", idx, score, key_to_string(key))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Checkpoint {
+    pub fn parse(input: &str) -> Result<Checkpoint, Error> {
+        let mut lines = input.lines();
+        if lines.next() != Some(CHECKPOINT_HEADER) {
+            return Err(Error::Checkpoint("not a checkpoint file".to_string()));
+        }
+        let mut checkpoint = Checkpoint { search: 0, wheels: Vec::new(), candidates: BTreeSet::new(), keys: Vec::new(), ciphertext: String::new(), options: Vec::new() };
+        for (line_idx, line) in lines.enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match (fields.first().copied(), fields.len()) {
+                (Some("search"), 2) => u64::from_str_radix(fields[1], 16).ok().map(|search| checkpoint.search = search),
+                (Some("ciphertext"), 2) => {
+                    checkpoint.ciphertext = fields[1].to_string();
+                    Some(())
+                },
+                // The value may contain spaces, e.g. --rotors 'II ? V', or be empty.
+                (Some("option"), _) => line.strip_prefix("option ").and_then(|rest| rest.split_once(' ')).map(|(option, value)|
+                    checkpoint.options.push((option.to_string(), value.to_string()))),
+                (Some("wheels"), 2) => fields[1].chars().map(|c| match c { '0' => Some(false), '1' => Some(true), _ => None })
+                    .collect::<Option<Vec<bool>>>().map(|wheels| checkpoint.wheels = wheels),
+                (Some("candidate"), 7) => match (fields[1].parse(), key_from_fields(&fields[2..])) {
+                    (Ok(score), Some(key)) => {
+                        checkpoint.candidates.insert((score, key));
+                        Some(())
+                    },
+                    _ => None,
+                },
+                (Some("pending"), 2) => {
+                    checkpoint.keys.push(None);
+                    Some(())
+                },
+                (Some("key"), 8) => match (fields[2].parse(), key_from_fields(&fields[3..])) {
+                    (Ok(score), Some(key)) => {
+                        checkpoint.keys.push(Some((score, key)));
+                        Some(())
                     },
+                    _ => None,
+                },
+                _ => None,
+            };
+            if parsed.is_none() {
+                return Err(Error::Checkpoint(format!("line {}: invalid checkpoint entry '{}'", line_idx + 2, line)));
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    // The number of wheel orders and reflectors that still have to be searched.
+    pub fn remaining(&self) -> usize {
+        self.wheels.iter().filter(|&&done| !done).count()
+    }
+
+    // The best candidate found so far, only its start positions are meaningful.
+    pub fn best(&self) -> Option<(f64, &encrypt::Key)> {
+        self.candidates.iter().next_back().map(|&(score, ref key)| (score as f64, key))
+    }
+
+    // The letters of the ciphertext of the search, empty for checkpoints written before
+    // it was recorded.
+    pub fn ciphertext(&self) -> &str {
+        &self.ciphertext
+    }
+
+    // The options recorded with with_options, e.g. the command-line options of the model
+    // and search, which the searches do not use.
+    pub fn options(&self) -> &[(String, String)] {
+        &self.options
+    }
+
+    pub fn with_options(self, options: Vec<(String, String)>) -> Checkpoint {
+        Checkpoint { options, .. self }
+    }
+}
+
+// Lets the caller follow a search and stop it, either by setting stop or after the
+// budget has elapsed. The search then returns the best keys found so far and sets stop
+// if it was not already set.
+pub struct Control<'a> {
+    pub observer: &'a (dyn Fn(&Progress) + Sync),
+    pub budget: Option<Duration>,
+    pub stop: &'a AtomicBool,
+    // Called with the state of the search at most once per checkpoint_interval and when
+    // the search ends, never if checkpoint_interval is None.
+    pub checkpoint: &'a (dyn Fn(&Checkpoint) + Sync),
+    pub checkpoint_interval: Option<Duration>,
+    // The state to resume the search from.
+    pub resume: Option<&'a Checkpoint>,
+}
+
+impl<'a> Control<'a> {
+    // A search only stopped through stop, without progress reports or checkpoints.
+    pub fn unlimited(stop: &'a AtomicBool) -> Control<'a> {
+        Control { observer: &|_| (), budget: None, stop, checkpoint: &|_| (), checkpoint_interval: None, resume: None }
+    }
+}
+
+// Tracks the progress of a stage over all the threads.
+struct Monitor<'a> {
+    control: &'a Control<'a>,
+    deadline: Option<Instant>,
+    stage: Stage,
+    start: Instant,
+    total: usize,
+    done: AtomicUsize,
+    best: Mutex<Option<(f64, encrypt::Key)>>,
+    last_checkpoint: Mutex<Instant>,
+}
+
+impl<'a> Monitor<'a> {
+    fn new(control: &'a Control<'a>, deadline: Option<Instant>, stage: Stage, done: usize, total: usize) -> Monitor<'a> {
+        Monitor {
+            control, deadline, stage, start: Instant::now(), total,
+            done: AtomicUsize::new(done), best: Mutex::new(None), last_checkpoint: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        if self.deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            self.control.stop.store(true, Ordering::SeqCst);
+        }
+        self.control.stop.load(Ordering::SeqCst)
+    }
+
+    fn step_done(&self, candidate: Option<(f64, &encrypt::Key)>, state: Option<&Checkpoint>) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut best = self.best.lock().unwrap();
+        if let Some((score, key)) = candidate {
+            if best.as_ref().is_none_or(|&(best_score, _)| best_score < score) {
+                *best = Some((score, key.clone()));
+            }
+        }
+        (self.control.observer)(&Progress {
+            stage: self.stage, done, total: self.total, best: best.clone(), elapsed: self.start.elapsed(),
+        });
+        if let Some(state) = state {
+            self.checkpoint(state, false);
+        }
+    }
+
+    fn checkpoint(&self, state: &Checkpoint, force: bool) {
+        if let Some(interval) = self.control.checkpoint_interval {
+            let mut last_checkpoint = self.last_checkpoint.lock().unwrap();
+            if force || interval <= last_checkpoint.elapsed() {
+                *last_checkpoint = Instant::now();
+                (self.control.checkpoint)(state);
+            }
+        }
+    }
+}
+
+// What the threads searching the wheel orders share.
+struct WheelSearch<'a> {
+    tables: &'a encrypt::Tables,
+    model: &'a NGramModel,
+    ciphertext: &'a [u8],
+    spec: &'a SearchSpec,
+    templates: &'a [encrypt::Key],
+}
+
+// The wheel orders and reflectors of the shard are distributed over the threads, the best
+// candidates for each of them being merged into state.
+fn brute_force_rotors_and_key(search: &WheelSearch, shard: &[usize], threads: usize, monitor: &Monitor, state: &Mutex<Checkpoint>) {
+    let WheelSearch { tables, model, ciphertext, spec, templates } = *search;
+    let threads = cmp::max(1, cmp::min(threads, shard.len()));
+    thread::scope(|scope| {
+        for thread_idx in 0..threads {
+            scope.spawn(move || {
+                for &idx in shard.iter().skip(thread_idx).step_by(threads) {
+                    if state.lock().unwrap().wheels[idx] { continue; }
+                    if monitor.stopped() { break; }
+                    let mut best_rotors_and_key = BTreeSet::new();
+                    brute_force_key(tables, model, ciphertext, spec, &templates[idx], &mut best_rotors_and_key);
+                    let mut state = state.lock().unwrap();
+                    state.wheels[idx] = true;
+                    for candidate in best_rotors_and_key.into_iter() {
+                        insert_bounded(&mut state.candidates, candidate, spec.candidates);
+                    }
+                    let best = state.candidates.iter().next_back().map(|&(score, ref key)| (score as f64, key.clone()));
+                    monitor.step_done(best.as_ref().map(|&(score, ref key)| (score, key)), Some(&state));
+                }
+            });
+        }
+    });
+}
+
+// Identifies a search for checkpoints, the number of results is not part of it as it
+// does not change the state of the search.
+fn search_id(ciphertext: &[u8], model: &NGramModel, spec: &SearchSpec) -> u64 {
+    let spec = format!("{:?}", SearchSpec { results: 0, .. spec.clone() });
+    let hash = ngram::fnv1a(model.fingerprint(), spec.as_bytes());
+    ngram::fnv1a(hash, ciphertext)
+}
+
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+// Tries the ring settings of the right rotor and then of the middle rotor, shifting the
+// rotor position by the same amount so that only the turnover points move. The left
+// ring only shifts the left rotor position so it is kept at A. Rings start from the
+// rightmost rotor whereas positions start from the leftmost one, after the greek wheel
+// which is left untouched. The right ring may only look right once the middle one is
+// known so both are searched again as long as this improves the score, each round taking
+// 2 * 26 = 52 trials, usually 104 in all, i.e. 169 times fewer than the 26^3 ring settings.
+// The position search may however have matched the part of the message after some rotors
+// stepped, leaving the middle or left position off by one. This shows as a stretch of the
+// decryption reading much worse than the rest, see reads_unevenly, in which case the
+// rounds are done again moving these positions back and forth by one, the left one even
+// for the right ring as the position search may have used a double step of the middle
+// rotor, e.g. from a notch at Z. That first round takes 2 * 26 * 9 = 468 trials.
+fn brute_force_rings(tables: &encrypt::Tables, model: &NGramModel, ciphertext : &Vec<u8>, key: &encrypt::Key) -> (f64, encrypt::Key) {
+    let (score, key, positions) = brute_force_rings_depth(tables, model, slice::from_ref(ciphertext), key, slice::from_ref(&key.positions));
+    (score, encrypt::Key { positions: positions[0].clone(), .. key })
+}
+
+// Same as brute_force_rings for messages sharing the rings, each one having its own start
+// positions. For each ring setting, the position shifts are chosen for each message.
+fn brute_force_rings_depth(tables: &encrypt::Tables, model: &NGramModel, ciphertexts: &[Vec<u8>], key: &encrypt::Key, positions: &[Vec<u8>]) -> (f64, encrypt::Key, Vec<Vec<u8>>) {
+    let score = |key: &encrypt::Key, positions: &Vec<u8>, ciphertext: &Vec<u8>| {
+        let key = encrypt::Key { positions: positions.clone(), .. key.clone() };
+        model.score_iter(encrypt::encrypt_iter_unchecked(tables, &key, ciphertext))
+    };
+    let total = ciphertexts.iter().zip(positions.iter()).map(|(ciphertext, positions)| score(key, positions, ciphertext)).sum();
+    let best = ring_rounds(&score, ciphertexts, (total, key.clone(), positions.to_vec()), &[(0, 0)]);
+    let missed_turnover = ciphertexts.iter().zip(best.2.iter()).any(|(ciphertext, positions)| {
+        let key = encrypt::Key { positions: positions.clone(), .. best.1.clone() };
+        let plaintext: Vec<u8> = encrypt::encrypt_iter_unchecked(tables, &key, ciphertext).collect();
+        reads_unevenly(model, &plaintext)
+    });
+    if !missed_turnover { return best; }
+    let shifts = [0u8, 1, 25];
+    let shifts: Vec<(u8, u8)> = shifts.iter().flat_map(|&middle_shift|
+        shifts.iter().map(move |&left_shift| (middle_shift, left_shift))).collect();
+    ring_rounds(&score, ciphertexts, best, &shifts)
+}
+
+// The rounds of brute_force_rings_depth starting from best, the first one also trying the
+// given shifts of the middle and left positions.
+fn ring_rounds<F>(score: &F, ciphertexts: &[Vec<u8>], mut best: (f64, encrypt::Key, Vec<Vec<u8>>), shifts: &[(u8, u8)]) -> (f64, encrypt::Key, Vec<Vec<u8>>)
+where F: Fn(&encrypt::Key, &Vec<u8>, &Vec<u8>) -> f64 {
+    let (left, middle) = (best.1.positions.len() - 3, best.1.positions.len() - 2);
+    let mut shifts = shifts;
+    loop {
+        let round_score = best.0;
+        for &(ring_idx, position_idx) in [(0, middle + 1), (1, middle)].iter() {
+            let (best_key, best_positions) = (best.1.clone(), best.2.clone());
+            for ring in 0..26 {
+                let mut key = best_key.clone();
+                key.rings[ring_idx] = ring;
+                let ring_shift = (26 + ring - best_key.rings[ring_idx]) % 26;
+                let mut total = 0.;
+                let mut ring_positions = Vec::new();
+                for (ciphertext, positions) in ciphertexts.iter().zip(best_positions.iter()) {
+                    let mut best_shift: Option<(f64, Vec<u8>)> = None;
+                    for &(middle_shift, left_shift) in shifts.iter() {
+                        let mut positions = positions.clone();
+                        positions[position_idx] = (positions[position_idx] + ring_shift) % 26;
+                        positions[middle] = (positions[middle] + middle_shift) % 26;
+                        positions[left] = (positions[left] + left_shift) % 26;
+                        let score = score(&key, &positions, ciphertext);
+                        if best_shift.as_ref().is_none_or(|&(best_score, _)| best_score < score) {
+                            best_shift = Some((score, positions));
+                        }
+                    }
+                    let (score, positions) = best_shift.unwrap();
+                    total += score;
+                    ring_positions.push(positions);
+                }
+                if best.0 < total {
+                    best = (total, key, ring_positions);
+                }
+            }
+        }
+        if best.0 <= round_score { return best; }
+        shifts = &[(0, 0)];
+    }
+}
+
+// A stretch of at least UNEVEN_MIN_NGRAMS n-grams reads as missing a turnover when it
+// scores less than UNEVEN_RATIO times as far above random text as the rest of the
+// plaintext, the rest reading as plaintext with a z-score of UNEVEN_Z_SCORE or more.
+// Real plaintext often has such a stretch too, e.g. a few rare words in a row, but the
+// candidates that do not read as plaintext are never retried. With the English
+// quadgrams, this retries the shifts for about a third of the candidates of 60 letters
+// and a tenth of those of 200 letters, finding the keys as often as always retrying.
+const UNEVEN_MIN_NGRAMS: usize = 6;
+const UNEVEN_RATIO: f64 = 0.3;
+const UNEVEN_Z_SCORE: f64 = 12.;
+
+// Whether some stretch of the plaintext reads much worse than the rest, as happens when
+// the middle or left rotor steps at the wrong letter. The stretch either reaches an end
+// of the plaintext or lies between two turnovers of the right rotor, less than 26 letters.
+fn reads_unevenly(model: &NGramModel, plaintext: &[u8]) -> bool {
+    let (mean, variance) = model.random_distribution();
+    let excess: Vec<f64> = model.ngram_scores(plaintext).iter().map(|score| score - mean).collect();
+    let len = excess.len();
+    let mut prefix = vec![0.];
+    for score in excess.iter() { prefix.push(prefix.last().unwrap() + score); }
+    for start in 0..len {
+        for end in start + UNEVEN_MIN_NGRAMS..=len {
+            if 0 < start && end < len && 26 < end - start { continue; }
+            let rest_len = len - (end - start);
+            if rest_len < UNEVEN_MIN_NGRAMS { break; }
+            let stretch = (prefix[end] - prefix[start]) / (end - start) as f64;
+            let rest = (prefix[len] - prefix[end] + prefix[start]) / rest_len as f64;
+            if UNEVEN_Z_SCORE <= rest * (rest_len as f64 / variance).sqrt() && stretch < UNEVEN_RATIO * rest { return true; }
+        }
+    }
+    false
+}
+
+// Small xorshift64* generator so that the randomised searches can be reproduced from a
+// seed without depending on an external crate.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // The seed goes through splitmix64 so that close seeds give unrelated sequences,
+        // the state must not be 0.
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        Rng { state: if z == 0 { 1 } else { z } }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    // Uniform in 0..n, the modulo bias is negligible for the small n used here.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    // Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn shuffle<T>(&mut self, values: &mut [T]) {
+        for i in (1..values.len()).rev() {
+            let j = self.below(i + 1);
+            values.swap(i, j);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Optimiser {
+    // Applies improving stecker changes until there are none left.
+    HillClimb,
+    // Random changes, worse ones being accepted with a probability that decreases with
+    // the temperature, followed by a hill climb.
+    Annealing,
+    // Hill climbs from random plugboards, keeping the best result.
+    RandomRestart,
+    // Always applies the best change, possibly a worse one, while forbidding recently
+    // changed pairs.
+    Tabu,
+}
+
+impl Optimiser {
+    pub fn from_name(name: &str) -> Option<Optimiser> {
+        match name.to_lowercase().as_str() {
+            "hill" | "hill-climb" => Some(Optimiser::HillClimb),
+            "anneal" | "annealing" => Some(Optimiser::Annealing),
+            "restart" | "random-restart" => Some(Optimiser::RandomRestart),
+            "tabu" => Some(Optimiser::Tabu),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Optimiser::HillClimb => "hill",
+            Optimiser::Annealing => "anneal",
+            Optimiser::RandomRestart => "restart",
+            Optimiser::Tabu => "tabu",
+        }
+    }
+}
+
+const RESTARTS: usize = 10;
+const ANNEALING_STEPS: usize = 20000;
+// Initial and final temperatures for a message of one letter, the score differences
+// growing with the message length.
+const ANNEALING_TEMPERATURES: (f64, f64) = (0.004, 0.0002);
+const TABU_TENURE: usize = 8;
+const TABU_PATIENCE: usize = 10;
+
+fn index_of_coincidence<I: Iterator<Item=u8>>(text: I) -> f64 {
+    let mut counts = [0u64; 26];
+    let mut len: u64 = 0;
+    for c in text {
+        counts[c as usize] += 1;
+        len += 1;
+    }
+    let coincidences: u64 = counts.iter().map(|&n| n * n.saturating_sub(1)).sum();
+    coincidences as f64 / cmp::max(1, len * len.saturating_sub(1)) as f64
+}
+
+// Scores the plugboards for a given key, summing the scores of several messages when they
+// share the key except for their start positions. The permutation applied by the rotors
+// and the reflector at each letter of a message does not depend on the plugboard so it is
+// only computed once.
+struct SteckerScorer<'a> {
+    model: &'a NGramModel,
+    messages: Vec<(&'a Vec<u8>, Vec<[u8; 26]>)>,
+    // Total length of the messages.
+    len: usize,
+    // The letters that are not part of a known pair.
+    free: Vec<u8>,
+}
+
+impl<'a> SteckerScorer<'a> {
+    fn new(tables: &encrypt::Tables, model: &'a NGramModel, ciphertexts: &'a [Vec<u8>], key: &encrypt::Key, positions: &[Vec<u8>]) -> SteckerScorer<'a> {
+        let messages = ciphertexts.iter().zip(positions.iter()).map(|(ciphertext, positions)| {
+            let unplugged = encrypt::Key { plugboard: encrypt::identity_plugboard(), positions: positions.clone(), .. key.clone() };
+            let mut inner = vec![[0u8; 26]; ciphertext.len()];
+            for c in 0..26u8 {
+                let input = vec![c; ciphertext.len()];
+                for (idx, d) in encrypt::encrypt_iter_unchecked(tables, &unplugged, &input).enumerate() {
+                    inner[idx][c as usize] = d;
+                }
+            }
+            (ciphertext, inner)
+        }).collect();
+        let free = (0..26u8).filter(|&c| key.plugboard[c as usize] == c).collect();
+        let len = ciphertexts.iter().map(|ciphertext| ciphertext.len()).sum();
+        SteckerScorer { model, messages, len, free }
+    }
+
+    fn decrypt<'b>(&'b self, message: &'b (&'a Vec<u8>, Vec<[u8; 26]>), plugboard: &'b [u8]) -> impl Iterator<Item=u8> + 'b {
+        message.0.iter().zip(message.1.iter()).map(move |(&c, inner)|
+            plugboard[inner[plugboard[c as usize] as usize] as usize])
+    }
+
+    fn score(&self, plugboard: &[u8]) -> f64 {
+        self.messages.iter().map(|message| self.model.score_iter(self.decrypt(message, plugboard))).sum()
+    }
+
+    fn score_ic(&self, plugboard: &[u8]) -> f64 {
+        index_of_coincidence(self.messages.iter().flat_map(|message| self.decrypt(message, plugboard)))
+    }
+
+    fn pairs(&self) -> Vec<(u8, u8)> {
+        let free = &self.free;
+        (0..free.len()).flat_map(|i| (i + 1..free.len()).map(move |j| (free[i], free[j]))).collect()
+    }
+}
+
+// The plugboards obtained by connecting a and b, or by disconnecting them if they already
+// are. The letters a and b were previously connected to are either left unconnected or
+// connected together.
+fn stecker_moves(plugboard: &[u8], a: u8, b: u8) -> Vec<Vec<u8>> {
+    let mut plugboard = plugboard.to_vec();
+    let (c, d) = (plugboard[a as usize], plugboard[b as usize]);
+    if c == b {
+        plugboard[a as usize] = a;
+        plugboard[b as usize] = b;
+        return vec![plugboard];
+    }
+    plugboard[c as usize] = c;
+    plugboard[d as usize] = d;
+    plugboard[a as usize] = b;
+    plugboard[b as usize] = a;
+    if c == a || d == b {
+        return vec![plugboard];
+    }
+    let mut swapped = plugboard.clone();
+    swapped[c as usize] = d;
+    swapped[d as usize] = c;
+    vec![plugboard, swapped]
+}
+
+// Applies the first improving change found until there are none left, using either the
+// index of coincidence or the n-gram score.
+fn climb(scorer: &SteckerScorer, plugboard: Vec<u8>, use_ic: bool) -> (f64, Vec<u8>) {
+    let score = |plugboard: &Vec<u8>| if use_ic { scorer.score_ic(plugboard) } else { scorer.score(plugboard) };
+    let pairs = scorer.pairs();
+    let mut best = (score(&plugboard), plugboard);
+    loop {
+        let mut improved = false;
+        for &(a, b) in pairs.iter() {
+            for plugboard in stecker_moves(&best.1, a, b).into_iter() {
+                let score = score(&plugboard);
+                if best.0 < score {
+                    best = (score, plugboard);
+                    improved = true;
+                }
+            }
+        }
+        if !improved { return best; }
+    }
+}
+
+// The n-gram score of a decryption with only some of the stecker pairs right is too
+// noisy to guide the search, so the index of coincidence, which does not depend on the
+// order of the letters, is used first. As it also tends to add spurious pairs, the
+// n-gram score is used on its own as well.
+fn hill_climb(scorer: &SteckerScorer, plugboard: Vec<u8>) -> (f64, Vec<u8>) {
+    let direct = climb(scorer, plugboard.clone(), false);
+    let (_, plugboard) = climb(scorer, plugboard, true);
+    let with_ic = climb(scorer, plugboard, false);
+    if direct.0 < with_ic.0 { with_ic } else { direct }
+}
+
+fn random_restart(scorer: &SteckerScorer, plugboard: Vec<u8>, rng: &mut Rng) -> (f64, Vec<u8>) {
+    let mut best = hill_climb(scorer, plugboard.clone());
+    for _restart in 1..RESTARTS {
+        let mut start = plugboard.clone();
+        let mut free = scorer.free.clone();
+        rng.shuffle(&mut free);
+        let n_pairs = rng.below(free.len() / 2 + 1);
+        for pair in free.chunks(2).take(n_pairs) {
+            start[pair[0] as usize] = pair[1];
+            start[pair[1] as usize] = pair[0];
+        }
+        let candidate = hill_climb(scorer, start);
+        if best.0 < candidate.0 {
+            best = candidate;
+        }
+    }
+    best
+}
+
+fn annealing(scorer: &SteckerScorer, plugboard: Vec<u8>, rng: &mut Rng) -> (f64, Vec<u8>) {
+    let free = &scorer.free;
+    if free.len() < 2 { return hill_climb(scorer, plugboard); }
+    let (initial, last) = ANNEALING_TEMPERATURES;
+    let (initial, last) = (initial * scorer.len as f64, last * scorer.len as f64);
+    let mut current = (scorer.score(&plugboard), plugboard);
+    let mut best = current.clone();
+    for step in 0..ANNEALING_STEPS {
+        let temperature = initial * (last / initial).powf(step as f64 / ANNEALING_STEPS as f64);
+        let a = rng.below(free.len());
+        let b = (a + 1 + rng.below(free.len() - 1)) % free.len();
+        let mut moves = stecker_moves(&current.1, free[a], free[b]);
+        let plugboard = moves.swap_remove(rng.below(moves.len()));
+        let score = scorer.score(&plugboard);
+        if current.0 < score || rng.next_f64() < ((score - current.0) / temperature).exp() {
+            current = (score, plugboard);
+            if best.0 < current.0 {
+                best = current.clone();
+            }
+        }
+    }
+    climb(scorer, best.1, false)
+}
+
+fn tabu(scorer: &SteckerScorer, plugboard: Vec<u8>) -> (f64, Vec<u8>) {
+    let pairs = scorer.pairs();
+    let mut current = (scorer.score(&plugboard), plugboard);
+    let mut best = current.clone();
+    let mut tabu_pairs = VecDeque::new();
+    let mut since_improvement = 0;
+    while since_improvement < TABU_PATIENCE {
+        let mut chosen: Option<(f64, Vec<u8>, (u8, u8))> = None;
+        for &pair in pairs.iter() {
+            let is_tabu = tabu_pairs.contains(&pair);
+            for plugboard in stecker_moves(&current.1, pair.0, pair.1).into_iter() {
+                let score = scorer.score(&plugboard);
+                // Tabu changes are still allowed when they lead to a new best plugboard.
+                if is_tabu && score <= best.0 { continue; }
+                if chosen.as_ref().is_none_or(|&(chosen_score, _, _)| chosen_score < score) {
+                    chosen = Some((score, plugboard, pair));
                 }
             }
         }
+        let (score, plugboard, pair) = match chosen {
+            None => break,
+            Some(chosen) => chosen,
+        };
+        current = (score, plugboard);
+        tabu_pairs.push_back(pair);
+        if TABU_TENURE < tabu_pairs.len() {
+            tabu_pairs.pop_front();
+        }
+        since_improvement += 1;
+        if best.0 < current.0 {
+            best = current.clone();
+            since_improvement = 0;
+        }
+    }
+    best
+}
+
+// Searches for the plugboard of key that maximizes the score, the pairs of key.plugboard
+// being kept as they are.
+pub fn recover_steckers(tables: &encrypt::Tables, model: &NGramModel, ciphertext: &Vec<u8>, key: &encrypt::Key, optimiser: Optimiser, rng: &mut Rng) -> Result<(f64, encrypt::Key), Error> {
+    key.check()?;
+    encrypt::check_input(ciphertext)?;
+    Ok(recover_steckers_unchecked(tables, model, ciphertext, key, optimiser, rng))
+}
+
+fn recover_steckers_unchecked(tables: &encrypt::Tables, model: &NGramModel, ciphertext: &Vec<u8>, key: &encrypt::Key, optimiser: Optimiser, rng: &mut Rng) -> (f64, encrypt::Key) {
+    recover_steckers_depth(tables, model, slice::from_ref(ciphertext), key, slice::from_ref(&key.positions), optimiser, rng)
+}
+
+// Same as recover_steckers for messages sharing key, each one having its own start
+// positions, the plugboard maximizing the sum of their scores being returned.
+fn recover_steckers_depth(tables: &encrypt::Tables, model: &NGramModel, ciphertexts: &[Vec<u8>], key: &encrypt::Key, positions: &[Vec<u8>], optimiser: Optimiser, rng: &mut Rng) -> (f64, encrypt::Key) {
+    let scorer = SteckerScorer::new(tables, model, ciphertexts, key, positions);
+    let plugboard = key.plugboard.clone();
+    let (score, plugboard) = match optimiser {
+        Optimiser::HillClimb => hill_climb(&scorer, plugboard),
+        Optimiser::Annealing => annealing(&scorer, hill_climb(&scorer, plugboard).1, rng),
+        Optimiser::RandomRestart => random_restart(&scorer, plugboard, rng),
+        Optimiser::Tabu => tabu(&scorer, hill_climb(&scorer, plugboard).1),
+    };
+    (score, encrypt::Key { plugboard, .. key.clone() })
+}
+
+pub struct Solution {
+    pub score: f64,
+    pub key: encrypt::Key,
+    pub plaintext: String,
+    pub confidence: Confidence,
+}
+
+// How far a decryption is from random text, which is what the decryption of a ciphertext
+// that cannot be broken looks like.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Confidence {
+    // Number of standard deviations between the score and the mean score of random text
+    // of the same length, treating the n-grams as independent.
+    pub z_score: f64,
+    pub ic: f64,
+    // Whether the z-score is above the one that the search reaches on random ciphertexts.
+    pub broken: bool,
+}
+
+// The best of the millions of keys tried always scores well above the average random
+// text. With the English quadgrams, the best decryptions of random ciphertexts of 20 to
+// 200 letters reach z-scores of 10 to 16, and 17 to 28 once the steckers are searched,
+// these decreasing with the length. Real messages of 50 letters or more are above.
+const BROKEN_Z_SCORE: f64 = 20.;
+const BROKEN_Z_SCORE_STECKERS: f64 = 30.;
+
+// The z-score above which the decryption of a search of spec is considered broken. When
+// the start positions of several messages are searched separately, as in depth, each
+// message gets its own random excess so the threshold grows with the square root of
+// their number.
+fn broken_z_score(spec: &SearchSpec, searched_messages: usize) -> f64 {
+    let threshold = if spec.steckers.is_some() { BROKEN_Z_SCORE_STECKERS } else { BROKEN_Z_SCORE };
+    threshold * (cmp::max(1, searched_messages) as f64).sqrt()
+}
+
+// The confidence for the decryptions of messages broken together.
+fn confidence(model: &NGramModel, threshold: f64, plaintexts: &[Vec<u8>]) -> Confidence {
+    let (mean, variance) = model.random_distribution();
+    let ngrams = plaintexts.iter().map(|plaintext| (plaintext.len() + 1).saturating_sub(model.n())).sum::<usize>() as f64;
+    let score: f64 = plaintexts.iter().map(|plaintext| model.score(plaintext)).sum();
+    let z_score = if ngrams == 0. { 0. } else { (score - ngrams * mean) / (ngrams * variance).sqrt() };
+    let ic = index_of_coincidence(plaintexts.iter().flat_map(|plaintext| plaintext.iter().copied()));
+    Confidence { z_score, ic, broken: threshold <= z_score }
+}
+
+// The confidence for a text scored on its own, e.g. the decryption with a known key.
+pub fn text_confidence(model: &NGramModel, text: &Vec<u8>) -> Confidence {
+    confidence(model, BROKEN_Z_SCORE, slice::from_ref(text))
+}
+
+fn initial_state(ciphertext: &[u8], model: &NGramModel, spec: &SearchSpec, n_templates: usize, control: &Control) -> Result<Checkpoint, Error> {
+    let search = search_id(ciphertext, model, spec);
+    match control.resume {
+        None => Ok(Checkpoint {
+            search, wheels: vec![false; n_templates], candidates: BTreeSet::new(), keys: Vec::new(),
+            ciphertext: ciphertext.iter().map(|&c| encrypt::chr(c)).collect(), options: Vec::new(),
+        }),
+        Some(checkpoint) if checkpoint.search == search && checkpoint.wheels.len() == n_templates => Ok(checkpoint.clone()),
+        Some(_) => Err(Error::Checkpoint("the checkpoint was made with a different ciphertext, model or search".to_string())),
+    }
+}
+
+// Searches the wheel orders and start positions for the shard-th part out of count of the
+// search space, shard starting from 0. The returned checkpoints for all the shards can then
+// be combined with merge_checkpoints, e.g. when the shards are searched on different
+// machines.
+pub fn search_shard(ciphertext : &str, model: &NGramModel, spec: &SearchSpec, shard: usize, count: usize, threads: usize, control: &Control) -> Result<Checkpoint, Error> {
+    spec.check()?;
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let ciphertext = encrypt::input_to_u8(ciphertext);
+    let templates = spec.templates();
+    let deadline = control.budget.map(|budget| Instant::now() + budget);
+    let state = Mutex::new(initial_state(&ciphertext, model, spec, templates.len(), control)?);
+    let indices: Vec<usize> = (0..templates.len()).filter(|idx| idx % count == shard).collect();
+    let done = indices.iter().filter(|&&idx| state.lock().unwrap().wheels[idx]).count();
+    let monitor = Monitor::new(control, deadline, Stage::Wheels, done, indices.len());
+    let search = WheelSearch { tables: &tables, model, ciphertext: &ciphertext, spec, templates: &templates };
+    brute_force_rotors_and_key(&search, &indices, threads, &monitor, &state);
+    let state = state.into_inner().unwrap();
+    monitor.checkpoint(&state, true);
+    Ok(state)
+}
+
+// Combines the checkpoints of different shards of the same search. The result can be used
+// to resume the search, which only searches the wheel orders not covered by any shard.
+pub fn merge_checkpoints(checkpoints: &[Checkpoint], spec: &SearchSpec) -> Result<Checkpoint, Error> {
+    let first = match checkpoints.first() {
+        None => return Err(Error::Checkpoint("no checkpoint to merge".to_string())),
+        Some(first) => first,
+    };
+    let mut merged = Checkpoint {
+        search: first.search, wheels: vec![false; first.wheels.len()], candidates: BTreeSet::new(), keys: Vec::new(),
+        ciphertext: first.ciphertext.clone(), options: first.options.clone(),
+    };
+    for checkpoint in checkpoints.iter() {
+        if checkpoint.search != merged.search || checkpoint.wheels.len() != merged.wheels.len() {
+            return Err(Error::Checkpoint("the checkpoints were made with different ciphertexts, models or searches".to_string()));
+        }
+        for (merged_done, &done) in merged.wheels.iter_mut().zip(checkpoint.wheels.iter()) {
+            *merged_done |= done;
+        }
+        for candidate in checkpoint.candidates.iter() {
+            insert_bounded(&mut merged.candidates, candidate.clone(), spec.candidates);
+        }
     }
-    return best_rotors_and_key;
+    Ok(merged)
 }
 
-pub fn brute_force(ciphertext : &str) -> Option<(f64, String, Vec<u8>, String)> {
+// Returns the spec.results best keys found, best first. When neither the rings nor the
+// positions are known, the keys are canonical in the sense that the rings are set to A
+// whenever this does not change the decryption, so the same key is only reported once.
+// The progress is reported to control which can also stop the search. When stopped during
+// the Wheels stage, the remaining wheel orders are skipped and the stecker search is
+// skipped for the remaining candidates.
+pub fn brute_force(ciphertext : &str, model: &NGramModel, spec: &SearchSpec, threads: usize, control: &Control) -> Result<Vec<Solution>, Error> {
+    spec.check()?;
     let world = encrypt::world();
+    let tables = encrypt::tables(&world);
     let ciphertext = encrypt::input_to_u8(ciphertext);
-    let mut maximum_score = 0. as f64;
-    let mut where_max = None;
-    let best_rotors_and_key = brute_force_rotors_and_key(&world, &ciphertext, &vec![0u8, 0, 0]);
-    for &(_score, ref key, ref rotor_config) in best_rotors_and_key.iter().rev() {
-        for rings in Product::new(26u8, 3us) {
-            let key = key.iter().zip(rings.iter()).map(|(&x, &y)| (x + y) % 26).collect();
-            let plaintext = encrypt::encrypt_u8(&world, &ciphertext, rotor_config, &key, &rings);
-            let s = score(&plaintext);
-            if maximum_score == 0. || maximum_score < s {
-                maximum_score = s;
-                where_max = Some((s, key, rotor_config.clone(), rings));
+    let search_rings = spec.rings.is_none() && spec.positions.is_none();
+    let templates = spec.templates();
+    let deadline = control.budget.map(|budget| Instant::now() + budget);
+    let state = Mutex::new(initial_state(&ciphertext, model, spec, templates.len(), control)?);
+    let all: Vec<usize> = (0..templates.len()).collect();
+    let done = state.lock().unwrap().wheels.iter().filter(|&&done| done).count();
+    let monitor = Monitor::new(control, deadline, Stage::Wheels, done, templates.len());
+    let search = WheelSearch { tables: &tables, model, ciphertext: &ciphertext, spec, templates: &templates };
+    brute_force_rotors_and_key(&search, &all, threads, &monitor, &state);
+    // The candidates are only final, and so the complete keys worth recording, when all the
+    // wheel orders have been searched.
+    let (candidates, record, done) = {
+        let mut state = state.lock().unwrap();
+        let candidates: Vec<encrypt::Key> = state.candidates.iter().rev().map(|(_, key)| key.clone()).collect();
+        let record = state.wheels.iter().all(|&done| done);
+        if record && state.keys.len() != candidates.len() {
+            state.keys = vec![None; candidates.len()];
+        }
+        let done = state.keys.iter().filter(|complete| complete.is_some()).count();
+        (candidates, record, done)
+    };
+    let monitor = Monitor::new(control, deadline, Stage::Keys, done, candidates.len());
+    // The ring and stecker searches are done independently for each candidate, each one
+    // using its own random generator so that the result does not depend on the threads.
+    let complete = |idx: usize, key: &encrypt::Key| {
+        if record {
+            if let Some(ref complete) = state.lock().unwrap().keys[idx] { return complete.clone(); }
+        }
+        let (score, key) =
+            if search_rings { brute_force_rings(&tables, model, &ciphertext, key) }
+            else { (model.score_iter(encrypt::encrypt_iter_unchecked(&tables, key, &ciphertext)), key.clone()) };
+        let stopped = monitor.stopped();
+        let (score, key) = match spec.steckers {
+            Some(optimiser) if !stopped => {
+                let mut rng = Rng::new(spec.seed.wrapping_add(idx as u64));
+                let (score, key) = recover_steckers_unchecked(&tables, model, &ciphertext, &key, optimiser, &mut rng);
+                // The rings were found without the stecker pairs, they can be refined now.
+                if search_rings { brute_force_rings(&tables, model, &ciphertext, &key) } else { (score, key) }
+            },
+            _ => (score, key),
+        };
+        let key = if search_rings { encrypt::canonical_key(&tables, &key, ciphertext.len()) } else { key };
+        let mut state = state.lock().unwrap();
+        if record && !(stopped && spec.steckers.is_some()) {
+            state.keys[idx] = Some((score, key.clone()));
+        }
+        monitor.step_done(Some((score, &key)), Some(&state));
+        (score, key)
+    };
+    let threads = cmp::max(1, cmp::min(threads, candidates.len()));
+    let mut keys: Vec<(f64, encrypt::Key)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|thread_idx| {
+            let (candidates, complete) = (&candidates, &complete);
+            scope.spawn(move || candidates.iter().enumerate().skip(thread_idx).step_by(threads)
+                .map(|(idx, key)| complete(idx, key)).collect::<Vec<_>>())
+        }).collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    monitor.checkpoint(&state.lock().unwrap(), true);
+    keys.sort_by(|&(s1, ref k1), &(s2, ref k2)| s2.partial_cmp(&s1).unwrap().then(k1.cmp(k2)));
+    keys.dedup_by(|&mut (_, ref k1), &mut (_, ref k2)| k1 == k2);
+    Ok(keys.into_iter().take(spec.results).map(|(score, key)| {
+        let plaintext = encrypt::encrypt_key_unchecked(&world, &key, &ciphertext);
+        let confidence = confidence(model, broken_z_score(spec, 1), slice::from_ref(&plaintext));
+        Solution { score, key, plaintext: plaintext.iter().map(|&c| encrypt::chr(c)).collect(), confidence }
+    }).collect())
+}
+
+pub struct DepthSolution {
+    pub score: f64,
+    // The key of each message, they only differ by their start positions.
+    pub keys: Vec<encrypt::Key>,
+    pub plaintexts: Vec<String>,
+    pub confidence: Confidence,
+}
+
+type DepthCandidate = (i64, encrypt::Key, Vec<Vec<u8>>);
+
+const DEPTH_ROUNDS: usize = 3;
+
+// Searches for the key shared by several messages, each one having its own start positions,
+// the score of a key being the sum of the scores of the messages. This can find the wheel
+// order, rings and steckers from messages that are each too short for brute_force.
+// For each wheel order only the best start positions of each message are kept. The start
+// positions of spec cannot be given, and checkpoints and shards are not supported.
+pub fn brute_force_depth(ciphertexts: &[&str], model: &NGramModel, spec: &SearchSpec, threads: usize, control: &Control) -> Result<Vec<DepthSolution>, Error> {
+    if ciphertexts.is_empty() {
+        return Err(Error::Search("no message to break".to_string()));
+    }
+    if spec.positions.is_some() {
+        return Err(Error::Search("the start positions are searched for each message in depth".to_string()));
+    }
+    if control.resume.is_some() {
+        return Err(Error::Search("a search in depth cannot be resumed".to_string()));
+    }
+    if !spec.greek.is_empty() {
+        return Err(Error::Search("the search in depth only supports three rotor machines".to_string()));
+    }
+    spec.check()?;
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let ciphertexts: Vec<Vec<u8>> = ciphertexts.iter().map(|ciphertext| encrypt::input_to_u8(ciphertext)).collect();
+    let templates = spec.templates();
+    let deadline = control.budget.map(|budget| Instant::now() + budget);
+    let monitor = Monitor::new(control, deadline, Stage::Wheels, 0, templates.len());
+    let best_candidates: Mutex<BTreeSet<DepthCandidate>> = Mutex::new(BTreeSet::new());
+    let threads = cmp::max(1, cmp::min(threads, templates.len()));
+    thread::scope(|scope| {
+        for thread_idx in 0..threads {
+            let (tables, templates, ciphertexts, best_candidates, monitor) = (&tables, &templates, &ciphertexts, &best_candidates, &monitor);
+            scope.spawn(move || {
+                for template in templates.iter().skip(thread_idx).step_by(threads) {
+                    if monitor.stopped() { break; }
+                    let scramblers = encrypt::scramblers_unchecked(tables, template);
+                    let mut total = 0.;
+                    let mut positions = Vec::new();
+                    for ciphertext in ciphertexts.iter() {
+                        let mut best = (f64::NEG_INFINITY, 0);
+                        score_positions(&scramblers, model, ciphertext, |index, score| if best.0 < score { best = (score, index) });
+                        total += best.0;
+                        positions.push(with_positions(template, &encrypt::index_key(best.1)).positions);
+                    }
+                    let mut best_candidates = best_candidates.lock().unwrap();
+                    insert_bounded(&mut best_candidates, (total as i64, template.clone(), positions), spec.candidates);
+                    let best = best_candidates.iter().next_back().map(|&(score, ref key, ref positions)|
+                        (score as f64, encrypt::Key { positions: positions[0].clone(), .. key.clone() }));
+                    monitor.step_done(best.as_ref().map(|&(score, ref key)| (score, key)), None);
+                }
+            });
+        }
+    });
+    let candidates: Vec<DepthCandidate> = best_candidates.into_inner().unwrap().into_iter().rev().collect();
+    let monitor = Monitor::new(control, deadline, Stage::Keys, 0, candidates.len());
+    let search_rings = spec.rings.is_none();
+    let total_score = |key: &encrypt::Key, positions: &Vec<Vec<u8>>| ciphertexts.iter().zip(positions.iter()).map(|(ciphertext, positions)|
+        model.score_iter(encrypt::encrypt_iter_unchecked(&tables, &encrypt::Key { positions: positions.clone(), .. key.clone() }, ciphertext))).sum::<f64>();
+    let complete = |idx: usize, (_, key, positions): &DepthCandidate| {
+        let (score, key, positions) =
+            if search_rings { brute_force_rings_depth(&tables, model, &ciphertexts, key, positions) }
+            else { (total_score(key, positions), key.clone(), positions.clone()) };
+        let mut best = (score, key, positions);
+        if let Some(optimiser) = spec.steckers {
+            // The start positions were found without the stecker pairs so they may be wrong
+            // for some messages, they are searched again using the pairs found so far.
+            let mut rng = Rng::new(spec.seed.wrapping_add(idx as u64));
+            let mut positions = best.2.clone();
+            for round in 0..DEPTH_ROUNDS {
+                if monitor.stopped() { break; }
+                if 0 < round {
+                    let scramblers = encrypt::scramblers_unchecked(&tables, &best.1);
+                    positions = ciphertexts.iter().map(|ciphertext| {
+                        let mut best_position = (f64::NEG_INFINITY, 0);
+                        score_positions(&scramblers, model, ciphertext, |index, score| if best_position.0 < score { best_position = (score, index) });
+                        with_positions(&best.1, &encrypt::index_key(best_position.1)).positions
+                    }).collect();
+                }
+                let (score, key) = recover_steckers_depth(&tables, model, &ciphertexts, &best.1, &positions, optimiser, &mut rng);
+                let (score, key, new_positions) =
+                    if search_rings { brute_force_rings_depth(&tables, model, &ciphertexts, &key, &positions) }
+                    else { (score, key, positions.clone()) };
+                if best.0 < score {
+                    best = (score, key, new_positions);
+                }
             }
         }
+        let (score, key, positions) = best;
+        monitor.step_done(Some((score, &encrypt::Key { positions: positions[0].clone(), .. key.clone() })), None);
+        (score, key, positions)
+    };
+    let threads = cmp::max(1, cmp::min(threads, candidates.len()));
+    let mut keys: Vec<(f64, encrypt::Key, Vec<Vec<u8>>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|thread_idx| {
+            let (candidates, complete) = (&candidates, &complete);
+            scope.spawn(move || candidates.iter().enumerate().skip(thread_idx).step_by(threads)
+                .map(|(idx, candidate)| complete(idx, candidate)).collect::<Vec<_>>())
+        }).collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    keys.sort_by(|&(s1, ref k1, ref p1), &(s2, ref k2, ref p2)| s2.partial_cmp(&s1).unwrap().then(k1.cmp(k2)).then(p1.cmp(p2)));
+    keys.dedup_by(|&mut (_, ref k1, ref p1), &mut (_, ref k2, ref p2)| k1 == k2 && p1 == p2);
+    Ok(keys.into_iter().take(spec.results).map(|(score, key, positions)| {
+        let keys: Vec<encrypt::Key> = positions.into_iter().map(|positions| encrypt::Key { positions, .. key.clone() }).collect();
+        let plaintexts: Vec<Vec<u8>> = keys.iter().zip(ciphertexts.iter()).map(|(key, ciphertext)| encrypt::encrypt_key_unchecked(&world, key, ciphertext)).collect();
+        let confidence = confidence(model, broken_z_score(spec, plaintexts.len()), &plaintexts);
+        let plaintexts = plaintexts.iter().map(|plaintext| plaintext.iter().map(|&c| encrypt::chr(c)).collect()).collect();
+        DepthSolution { score, keys, plaintexts, confidence }
+    }).collect())
+}
+
+// A message as transmitted with enciphered indicators: the start position in clear, the
+// message key encrypted at that position with the day key, possibly twice, and the
+// message encrypted at the message key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transmission {
+    pub start: Vec<u8>,
+    pub indicator: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Transmission {
+    // Parses "START INDICATOR TEXT", e.g. "RTJ WXCWXC NQBFK LOPWA", the indicator having 3
+    // letters or 6 when the message key was doubled.
+    pub fn parse(line: &str) -> Result<Transmission, Error> {
+        let mut fields = line.split_whitespace();
+        let start = fields.next().map(encrypt::input_to_u8).unwrap_or_default();
+        let indicator = fields.next().map(encrypt::input_to_u8).unwrap_or_default();
+        let ciphertext: Vec<u8> = fields.flat_map(|group| encrypt::input_to_u8(group).into_iter()).collect();
+        if start.len() != 3 || (indicator.len() != 3 && indicator.len() != 6) || ciphertext.is_empty() {
+            return Err(Error::Transmission(format!("expected 'START INDICATOR TEXT' with a 3 letter start and a 3 or 6 letter indicator, got '{}'", line.trim())));
+        }
+        Ok(Transmission { start, indicator, ciphertext })
+    }
+
+    // The message key encrypted by the indicator for the day key, None if it was doubled and
+    // the two halves do not match.
+    fn message_key(&self, tables: &encrypt::Tables, day_key: &encrypt::Key) -> Option<Vec<u8>> {
+        let key = encrypt::Key { positions: self.start.clone(), .. day_key.clone() };
+        let decoded: Vec<u8> = encrypt::encrypt_iter_unchecked(tables, &key, &self.indicator).collect();
+        if decoded[3..] != decoded[..decoded.len() - 3] { return None; }
+        Some(decoded[..3].to_vec())
     }
-    match where_max {
-        None => None,
-        Some((score, key, rotors, rings)) => {
-            let key: String = key.iter().map(|&x| (x as u8 + 'A' as u8) as char).collect();
-            let rings: String = rings.iter().map(|&x| (x as u8 + 'A' as u8) as char).collect();
-            Some((score, key, rotors, rings))
+}
+
+pub struct IndicatorSolution {
+    pub score: f64,
+    // The key shared by the transmissions, the start positions being those of the first one.
+    pub day_key: encrypt::Key,
+    // The message key of each transmission, as read from its indicator.
+    pub message_keys: Vec<Vec<u8>>,
+    pub plaintexts: Vec<String>,
+    pub confidence: Confidence,
+}
+
+// The message keys given by the indicators for day_key and the sum of the scores of the
+// messages, None if a doubled indicator is inconsistent.
+fn score_indicators(tables: &encrypt::Tables, model: &NGramModel, transmissions: &[Transmission], day_key: &encrypt::Key) -> Option<(f64, Vec<Vec<u8>>)> {
+    let mut total = 0.;
+    let mut message_keys = Vec::new();
+    for transmission in transmissions.iter() {
+        let message_key = transmission.message_key(tables, day_key)?;
+        let key = encrypt::Key { positions: message_key.clone(), .. day_key.clone() };
+        total += model.score_iter(encrypt::encrypt_iter_unchecked(tables, &key, &transmission.ciphertext));
+        message_keys.push(message_key);
+    }
+    Some((total, message_keys))
+}
+
+// Recovers the day key from complete transmissions, trying each wheel order and ring
+// setting: the indicators are decrypted at their start positions and the messages at the
+// resulting message keys. As the start positions are sent in clear all the rings matter,
+// so 26^3 ring settings are tried unless they are given by spec. The indicators go
+// through the plugboard so the pairs of spec are used as is, the unknown ones being
+// searched afterwards when spec.steckers is set. The positions of spec cannot be given,
+// and checkpoints and shards are not supported.
+pub fn brute_force_indicators(transmissions: &[Transmission], model: &NGramModel, spec: &SearchSpec, threads: usize, control: &Control) -> Result<Vec<IndicatorSolution>, Error> {
+    if transmissions.is_empty() {
+        return Err(Error::Search("no transmission to break".to_string()));
+    }
+    if spec.positions.is_some() {
+        return Err(Error::Search("the start positions are given by each transmission".to_string()));
+    }
+    if control.resume.is_some() {
+        return Err(Error::Search("a search with indicators cannot be resumed".to_string()));
+    }
+    if !spec.greek.is_empty() {
+        return Err(Error::Search("the search with indicators only supports three rotor machines".to_string()));
+    }
+    spec.check()?;
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let templates = spec.templates();
+    let rings: Vec<Vec<u8>> = match spec.rings {
+        Some(ref rings) => vec![rings.clone()],
+        None => (0..26 * 26 * 26).map(|index| encrypt::index_key(index).to_vec()).collect(),
+    };
+    let deadline = control.budget.map(|budget| Instant::now() + budget);
+    let monitor = Monitor::new(control, deadline, Stage::Wheels, 0, templates.len());
+    let best_candidates: Mutex<BTreeSet<DepthCandidate>> = Mutex::new(BTreeSet::new());
+    let threads = cmp::max(1, cmp::min(threads, templates.len()));
+    thread::scope(|scope| {
+        for thread_idx in 0..threads {
+            let (tables, templates, rings, best_candidates, monitor) = (&tables, &templates, &rings, &best_candidates, &monitor);
+            scope.spawn(move || {
+                for template in templates.iter().skip(thread_idx).step_by(threads) {
+                    if monitor.stopped() { break; }
+                    let mut candidates = BTreeSet::new();
+                    let mut key = template.clone();
+                    for ring_setting in rings.iter() {
+                        key.rings = ring_setting.clone();
+                        if let Some((score, message_keys)) = score_indicators(tables, model, transmissions, &key) {
+                            insert_bounded(&mut candidates, (score as i64, key.clone(), message_keys), spec.candidates);
+                        }
+                    }
+                    let mut best_candidates = best_candidates.lock().unwrap();
+                    for candidate in candidates.into_iter() {
+                        insert_bounded(&mut best_candidates, candidate, spec.candidates);
+                    }
+                    let best = best_candidates.iter().next_back().map(|&(score, ref key, _)| (score as f64, key.clone()));
+                    monitor.step_done(best.as_ref().map(|&(score, ref key)| (score, key)), None);
+                }
+            });
+        }
+    });
+    let candidates: Vec<DepthCandidate> = best_candidates.into_inner().unwrap().into_iter().rev().collect();
+    let monitor = Monitor::new(control, deadline, Stage::Keys, 0, candidates.len());
+    let ciphertexts: Vec<Vec<u8>> = transmissions.iter().map(|transmission| transmission.ciphertext.clone()).collect();
+    let complete = |idx: usize, (_, key, message_keys): &DepthCandidate| {
+        let mut best = score_indicators(&tables, model, transmissions, key).map(|(score, _)| (score, key.clone(), message_keys.clone())).unwrap();
+        if let Some(optimiser) = spec.steckers {
+            // The pairs change the message keys read from the indicators, which are read
+            // again before searching for more pairs.
+            let mut rng = Rng::new(spec.seed.wrapping_add(idx as u64));
+            for _ in 0..DEPTH_ROUNDS {
+                if monitor.stopped() { break; }
+                let (_, key) = recover_steckers_depth(&tables, model, &ciphertexts, &best.1, &best.2, optimiser, &mut rng);
+                match score_indicators(&tables, model, transmissions, &key) {
+                    Some((score, message_keys)) if best.0 < score => best = (score, key, message_keys),
+                    _ => break,
+                }
+            }
+        }
+        monitor.step_done(Some((best.0, &best.1)), None);
+        best
+    };
+    let threads = cmp::max(1, cmp::min(threads, candidates.len()));
+    let mut keys: Vec<(f64, encrypt::Key, Vec<Vec<u8>>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|thread_idx| {
+            let (candidates, complete) = (&candidates, &complete);
+            scope.spawn(move || candidates.iter().enumerate().skip(thread_idx).step_by(threads)
+                .map(|(idx, candidate)| complete(idx, candidate)).collect::<Vec<_>>())
+        }).collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    keys.sort_by(|&(s1, ref k1, _), &(s2, ref k2, _)| s2.partial_cmp(&s1).unwrap().then(k1.cmp(k2)));
+    keys.dedup_by(|&mut (_, ref k1, _), &mut (_, ref k2, _)| k1 == k2);
+    Ok(keys.into_iter().take(spec.results).map(|(score, key, message_keys)| {
+        let plaintexts: Vec<Vec<u8>> = transmissions.iter().zip(message_keys.iter()).map(|(transmission, message_key)| {
+            let key = encrypt::Key { positions: message_key.clone(), .. key.clone() };
+            encrypt::encrypt_key_unchecked(&world, &key, &transmission.ciphertext)
+        }).collect();
+        let confidence = confidence(model, broken_z_score(spec, 1), &plaintexts);
+        let plaintexts = plaintexts.iter().map(|plaintext| plaintext.iter().map(|&c| encrypt::chr(c)).collect()).collect();
+        let day_key = encrypt::Key { positions: transmissions[0].start.clone(), .. key };
+        IndicatorSolution { score, day_key, message_keys, plaintexts, confidence }
+    }).collect())
+}
+
+// A setting at which the crib is consistent with the ciphertext, as found by bombe.
+pub struct BombeStop {
+    // The key at which the message starts, its plugboard holding the pairs deduced from
+    // the crib in addition to the known ones.
+    pub key: encrypt::Key,
+    // Position of the crib in the message.
+    pub offset: usize,
+    pub plaintext: String,
+}
+
+const UNKNOWN: u8 = 26;
+
+// Sets the stecker partner of a to b, and of b to a, returning false if this contradicts
+// the partners already set. The letters that got a partner are queued.
+fn assign_stecker(steckers: &mut [u8; 26], queue: &mut Vec<u8>, a: u8, b: u8) -> bool {
+    if steckers[a as usize] == b { return true; }
+    if steckers[a as usize] != UNKNOWN || steckers[b as usize] != UNKNOWN { return false; }
+    steckers[a as usize] = b;
+    steckers[b as usize] = a;
+    queue.push(a);
+    if a != b { queue.push(b); }
+    true
+}
+
+// Follows the consequences of the partners queued through the menu: as the plugboard is
+// applied on both sides of the scrambler, the partner of the ciphertext letter is the
+// scrambler output for the partner of the crib letter and conversely. This is the
+// diagonal board of the Turing-Welchman bombe.
+fn propagate_steckers(menu: &[Vec<(u8, usize)>], window: &[&[u8; 26]], steckers: &mut [u8; 26], queue: &mut Vec<u8>) -> bool {
+    while let Some(a) = queue.pop() {
+        let partner = steckers[a as usize] as usize;
+        for &(b, idx) in menu[a as usize].iter() {
+            if !assign_stecker(steckers, queue, b, window[idx][partner]) { return false; }
         }
     }
+    true
+}
+
+// The stecker partners consistent with the menu, for each partner of the test letter
+// that does not lead to a contradiction.
+fn bombe_test(menu: &[Vec<(u8, usize)>], window: &[&[u8; 26]], known: &[u8; 26], test_letter: u8) -> Vec<[u8; 26]> {
+    let mut stops = Vec::new();
+    let mut queue = Vec::new();
+    for partner in 0..26u8 {
+        let mut steckers = *known;
+        queue.clear();
+        if assign_stecker(&mut steckers, &mut queue, test_letter, partner) &&
+            propagate_steckers(menu, window, &mut steckers, &mut queue) {
+            stops.push(steckers);
+        }
+    }
+    stops
+}
+
+// The offsets at which the crib can be, i.e. where no letter would be enciphered to itself.
+fn crib_offsets(ciphertext: &[u8], crib: &[u8]) -> Vec<usize> {
+    (0..(ciphertext.len() + 1).saturating_sub(crib.len()))
+        .filter(|&offset| crib.iter().zip(ciphertext[offset..].iter()).all(|(p, c)| p != c)).collect()
+}
+
+// The smallest amount by which to turn the ring and the position of a rotor showing window,
+// which does not change the scrambler, so that it does not reach a turnover within the
+// given number of steps, or reaches it as late as possible.
+fn turnover_shift(rotor: u8, window: u8, steps: usize) -> u8 {
+    let turnovers = encrypt::rotor_turnovers(rotor);
+    let distance = |shift: u8| turnovers.iter().map(|&t| ((t + 52 - window - shift) % 26) as usize).min().unwrap();
+    match (0..26u8).find(|&shift| steps <= distance(shift)) {
+        Some(shift) => shift,
+        None => (0..26u8).max_by_key(|&shift| (distance(shift), cmp::Reverse(shift))).unwrap(),
+    }
+}
+
+// What the threads running the bombe on the wheel orders share.
+struct BombeRun<'a> {
+    tables: &'a encrypt::Tables,
+    ciphertext: &'a [u8],
+    crib: &'a [u8],
+    offsets: &'a [usize],
+    known: &'a [u8; 26],
+    free_rings: bool,
+    // The number of stops after which to stop, as only the first ones are returned.
+    limit: usize,
 }
 
+// The stops of the bombe for one wheel order, trying all the rotor positions at each
+// offset of the crib, as the keys with the deduced pairs and the offsets of the crib.
+// When the rings are not known, the right and middle ones are chosen so that the middle
+// rotor does not move until the end of the crib if possible, the plaintext of the stop
+// being then readable up to there.
+fn bombe_template(run: &BombeRun, template: &encrypt::Key) -> Vec<(encrypt::Key, usize)> {
+    let BombeRun { tables, ciphertext, crib, offsets, known, free_rings, limit } = *run;
+    let unplugged = encrypt::Key { plugboard: encrypt::identity_plugboard(), .. template.clone() };
+    let scramblers = encrypt::scramblers_unchecked(tables, &unplugged);
+    let mut stops = Vec::new();
+    for &offset in offsets.iter() {
+        let mut menu = vec![Vec::new(); 26];
+        for (idx, (&p, &c)) in crib.iter().zip(ciphertext[offset..].iter()).enumerate() {
+            menu[p as usize].push((c, idx));
+            menu[c as usize].push((p, idx));
+        }
+        let test_letter = (0..26u8).max_by_key(|&c| (menu[c as usize].len(), cmp::Reverse(c))).unwrap();
+        for left in 0..26u8 {
+            for middle in 0..26u8 {
+                let row: Vec<&[u8; 26]> = (0..26u8).map(|right| scramblers.scrambler(encrypt::key_index(&[left, middle, right]))).collect();
+                for right in 0..26 {
+                    let window: Vec<&[u8; 26]> = (0..crib.len()).map(|idx| row[(right + idx) % 26]).collect();
+                    for steckers in bombe_test(&menu, &window, known, test_letter).into_iter() {
+                        let start = ((right + 2 * 26 - offset % 26 - 1) % 26) as u8;
+                        let mut key = with_positions(template, &[left, middle, start]);
+                        if free_rings {
+                            let n = key.positions.len();
+                            for (ring, position, steps) in [(0, n - 1, offset + crib.len()), (1, n - 2, 1)] {
+                                let shift = turnover_shift(key.rotors[ring], key.positions[position], steps);
+                                key.rings[ring] = (key.rings[ring] + shift) % 26;
+                                key.positions[position] = (key.positions[position] + shift) % 26;
+                            }
+                        }
+                        for (plug, &stecker) in key.plugboard.iter_mut().zip(steckers.iter()) {
+                            if stecker != UNKNOWN { *plug = stecker; }
+                        }
+                        stops.push((key, offset));
+                        if limit <= stops.len() { return stops; }
+                    }
+                }
+            }
+        }
+    }
+    stops
+}
+
+// Searches the settings at which crib, a known part of the plaintext, is consistent with
+// the ciphertext at offset, or at all the possible offsets when None. As on the bombe,
+// the middle rotor is assumed not to move while the crib is enciphered, nor before, the
+// unknown right and middle rings being set accordingly. When it does, the key may have to
+// be corrected by breaking the message with the wheel order and steckers found. The known
+// pairs of spec are used and the stops are returned in the order of spec's wheel orders,
+// at most spec.candidates of them. The positions of spec cannot be given, and
+// checkpoints and shards are not supported.
+pub fn bombe(ciphertext: &str, crib: &str, offset: Option<usize>, spec: &SearchSpec, threads: usize, control: &Control) -> Result<Vec<BombeStop>, Error> {
+    spec.check()?;
+    let ciphertext = encrypt::input_to_u8(ciphertext);
+    let crib = encrypt::input_to_u8(crib);
+    if crib.is_empty() || ciphertext.len() < crib.len() {
+        return Err(Error::Search("the crib should be non-empty and fit in the message".to_string()));
+    }
+    if spec.positions.is_some() {
+        return Err(Error::Search("the bombe searches the start positions".to_string()));
+    }
+    if control.resume.is_some() {
+        return Err(Error::Search("a bombe run cannot be resumed".to_string()));
+    }
+    let offsets = match offset {
+        Some(offset) if crib_offsets(&ciphertext, &crib).contains(&offset) => vec![offset],
+        Some(offset) => return Err(Error::Search(format!("the crib cannot be at offset {}, a letter would be enciphered to itself", offset))),
+        None => crib_offsets(&ciphertext, &crib),
+    };
+    let mut known = [UNKNOWN; 26];
+    for (a, &b) in spec.plugboard.iter().enumerate() {
+        if a as u8 != b { known[a] = b; }
+    }
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let templates = spec.templates();
+    let free_rings = spec.rings.is_none();
+    let deadline = control.budget.map(|budget| Instant::now() + budget);
+    let monitor = Monitor::new(control, deadline, Stage::Wheels, 0, templates.len());
+    let run = BombeRun { tables: &tables, ciphertext: &ciphertext, crib: &crib, offsets: &offsets, known: &known, free_rings, limit: spec.candidates };
+    let threads = cmp::max(1, cmp::min(threads, templates.len()));
+    let mut stops: Vec<(usize, Vec<(encrypt::Key, usize)>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|thread_idx| {
+            let (run, templates, monitor) = (&run, &templates, &monitor);
+            scope.spawn(move || {
+                let mut res = Vec::new();
+                for (template_idx, template) in templates.iter().enumerate().skip(thread_idx).step_by(threads) {
+                    if monitor.stopped() { break; }
+                    res.push((template_idx, bombe_template(run, template)));
+                    monitor.step_done(None, None);
+                }
+                res
+            })
+        }).collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    stops.sort_by_key(|&(template_idx, _)| template_idx);
+    Ok(stops.into_iter().flat_map(|(_, stops)| stops.into_iter()).take(spec.candidates).map(|(key, offset)| {
+        let plaintext = encrypt::encrypt_key_unchecked(&world, &key, &ciphertext).iter().map(|&c| encrypt::chr(c)).collect();
+        BombeStop { key, offset, plaintext }
+    }).collect())
+}
+
+#[test]
+fn stecker_tests() {
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let plaintext = encrypt::input_to_u8("ITWASTHEBESTOFTIMESITWASTHEWORSTOFTIMESITWASTHEAGEOFWISDOMITWASTHEAGEOFFOOLISHNESSITWASTHEEPOCHOFBELIEFITWASTHEEPOCHOFINCREDULITY");
+    let mut key = encrypt::Key::new(vec![3, 0, 2], vec![7, 11, 2], vec![4, 0, 19]);
+    key.plugboard = encrypt::parse_plugboard("AQ BT CX DM FK GW HN IR").unwrap();
+    let ciphertext = encrypt::encrypt_key_unchecked(&world, &key, &plaintext);
+    let known = encrypt::Key { plugboard: encrypt::parse_plugboard("AQ").unwrap(), .. key.clone() };
+    for &optimiser in [Optimiser::HillClimb, Optimiser::Annealing, Optimiser::RandomRestart, Optimiser::Tabu].iter() {
+        let (score, found) = recover_steckers(&tables, &model, &ciphertext, &known, optimiser, &mut Rng::new(42)).unwrap();
+        assert_eq!(found, key, "{}", optimiser.name());
+        assert_eq!(score, model.score(&plaintext));
+        assert_eq!(Optimiser::from_name(optimiser.name()), Some(optimiser));
+    }
+    let moves = stecker_moves(&encrypt::parse_plugboard("AB CD").unwrap(), 0, 2);
+    assert_eq!(moves, vec![encrypt::parse_plugboard("AC").unwrap(), encrypt::parse_plugboard("AC BD").unwrap()]);
+    let mut rng = Rng::new(1);
+    let mut other = Rng::new(1);
+    assert!((0..100).all(|_| rng.next_u64() == other.next_u64()));
+}
+
+#[test]
+fn checkpoint_tests() {
+    let world = encrypt::world();
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let plaintext = encrypt::input_to_u8("THEQUICKBROWNFOXJUMPSOVERTHELAZYDOGANDTHENITRANSAWAYINTOTHEFOREST");
+    let key = encrypt::Key::new(vec![0, 1, 2], vec![10, 3, 23], vec![2, 5, 0]);
+    let ciphertext: String = encrypt::encrypt_key_unchecked(&world, &key, &plaintext).iter().map(|&c| encrypt::chr(c)).collect();
+    let mut spec = SearchSpec::new();
+    spec.rotors = vec![None, Some(1), Some(2)];
+    spec.results = 3;
+    let stop = AtomicBool::new(false);
+    let saved = Mutex::new(None);
+    let save = |checkpoint: &Checkpoint| *saved.lock().unwrap() = Some(checkpoint.to_string());
+    let search = |observer: &(dyn Fn(&Progress) + Sync), resume: Option<&Checkpoint>| {
+        let control = Control { observer, checkpoint: &save, checkpoint_interval: Some(Duration::from_secs(0)), resume, .. Control::unlimited(&stop) };
+        brute_force(&ciphertext, &model, &spec, 1, &control).unwrap()
+    };
+    let expected = search(&|_| (), None);
+    // Stops after the first wheel order, resuming from the saved state.
+    search(&|_| stop.store(true, Ordering::SeqCst), None);
+    let checkpoint = Checkpoint::parse(saved.lock().unwrap().as_ref().unwrap()).unwrap();
+    assert_eq!(checkpoint.wheels, vec![true, false, false]);
+    assert_eq!(checkpoint.to_string(), *saved.lock().unwrap().as_ref().unwrap());
+    stop.store(false, Ordering::SeqCst);
+    let resumed = search(&|_| (), Some(&checkpoint));
+    assert_eq!(resumed.iter().map(|s| (s.score, &s.key)).collect::<Vec<_>>(),
+               expected.iter().map(|s| (s.score, &s.key)).collect::<Vec<_>>());
+    assert_eq!(expected[0].key, encrypt::Key { rings: vec![2, 5, 0], .. key });
+    let control = Control::unlimited(&stop);
+    let shards: Vec<Checkpoint> = (0..2).map(|shard| search_shard(&ciphertext, &model, &spec, shard, 2, 1, &control).unwrap()).collect();
+    assert_eq!(shards[0].wheels, vec![true, false, true]);
+    let merged = merge_checkpoints(&shards, &spec).unwrap();
+    assert_eq!(merged.remaining(), 0);
+    assert_eq!(merged.ciphertext(), ciphertext);
+    // The options survive the file, even with spaces or empty values.
+    let options = vec![("--rotors".to_string(), "? II III".to_string()), ("--plugboard".to_string(), String::new())];
+    let recorded = Checkpoint::parse(&merged.clone().with_options(options.clone()).to_string()).unwrap();
+    assert_eq!(recorded.options(), &options[..]);
+    assert_eq!(recorded.ciphertext(), ciphertext);
+    let merged = search(&|_| (), Some(&merged));
+    assert_eq!(merged.iter().map(|s| (s.score, &s.key)).collect::<Vec<_>>(),
+               expected.iter().map(|s| (s.score, &s.key)).collect::<Vec<_>>());
+    spec.candidates = 10;
+    assert!(brute_force(&ciphertext, &model, &spec, 1, &Control { resume: Some(&checkpoint), .. Control::unlimited(&stop) }).is_err());
+    assert!(Checkpoint::parse("enigma-checkpoint 1\nwheels 012\n").is_err());
+}
+
+#[test]
+fn depth_tests() {
+    let world = encrypt::world();
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let key = encrypt::Key::new(vec![0, 1, 2], vec![0, 0, 0], vec![2, 5, 0]);
+    let messages = [
+        ("ATTACKATDAWNONTHEEASTERNFRONT", vec![16, 22, 4]),
+        ("THEWEATHERTODAYISCLEARANDCOLD", vec![0, 1, 2]),
+        ("SUPPLIESWILLARRIVEBYTRAINTONIGHT", vec![23, 24, 25]),
+        ("ALLUNITSREPORTTOHEADQUARTERSNOW", vec![12, 11, 10]),
+    ];
+    let ciphertexts: Vec<String> = messages.iter().map(|&(plaintext, ref positions)| {
+        let key = encrypt::Key { positions: positions.clone(), .. key.clone() };
+        encrypt::encrypt_key_unchecked(&world, &key, &encrypt::input_to_u8(plaintext)).iter().map(|&c| encrypt::chr(c)).collect()
+    }).collect();
+    let ciphertexts: Vec<&str> = ciphertexts.iter().map(|c| c.as_str()).collect();
+    let mut spec = SearchSpec::new();
+    spec.rotors = vec![None, Some(1), Some(2)];
+    spec.steckers = Some(Optimiser::HillClimb);
+    let stop = AtomicBool::new(false);
+    let control = Control::unlimited(&stop);
+    let solutions = brute_force_depth(&ciphertexts, &model, &spec, 1, &control).unwrap();
+    let plaintexts: Vec<&str> = solutions[0].plaintexts.iter().map(|p| p.as_str()).collect();
+    assert_eq!(plaintexts, messages.iter().map(|&(plaintext, _)| plaintext).collect::<Vec<_>>());
+    assert_eq!(solutions[0].keys[0].plugboard, key.plugboard);
+    spec.positions = Some(vec![0, 0, 0]);
+    assert!(brute_force_depth(&ciphertexts, &model, &spec, 1, &control).is_err());
+}
+
+#[test]
+fn indicator_tests() {
+    let world = encrypt::world();
+    let tables = encrypt::tables(&world);
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let mut day_key = encrypt::Key::new(vec![0, 1, 2], vec![0, 0, 0], vec![2, 5, 11]);
+    day_key.plugboard = encrypt::parse_plugboard("AQ BT").unwrap();
+    let messages = [
+        ("RTJ", vec![16, 22, 4], "ATTACKATDAWNONTHEEASTERNFRONT"),
+        ("AAA", vec![7, 1, 19], "THEWEATHERTODAYISCLEARANDCOLD"),
+        ("MZQ", vec![23, 24, 25], "SUPPLIESWILLARRIVEBYTRAINTONIGHT"),
+    ];
+    let encrypt = |key: &encrypt::Key, text: &Vec<u8>| -> String { encrypt::encrypt_iter_unchecked(&tables, key, text).map(encrypt::chr).collect() };
+    let lines: Vec<String> = messages.iter().enumerate().map(|(idx, &(start, ref message_key, plaintext))| {
+        let mut doubled = message_key.clone();
+        if idx == 0 { doubled.extend(message_key.iter()); }
+        let indicator = encrypt(&encrypt::Key { positions: encrypt::input_to_u8(start), .. day_key.clone() }, &doubled);
+        let ciphertext = encrypt(&encrypt::Key { positions: message_key.clone(), .. day_key.clone() }, &encrypt::input_to_u8(plaintext));
+        format!("{} {} {}", start, indicator, ciphertext)
+    }).collect();
+    let transmissions: Vec<Transmission> = lines.iter().map(|line| Transmission::parse(line).unwrap()).collect();
+    assert_eq!(transmissions[0].indicator.len(), 6);
+    let mut spec = SearchSpec::new();
+    spec.rotors = vec![None, Some(1), Some(2)];
+    spec.plugboard = day_key.plugboard.clone();
+    let stop = AtomicBool::new(false);
+    let control = Control::unlimited(&stop);
+    let solutions = brute_force_indicators(&transmissions, &model, &spec, 1, &control).unwrap();
+    assert_eq!(solutions[0].day_key, encrypt::Key { positions: encrypt::input_to_u8("RTJ"), .. day_key.clone() });
+    assert_eq!(solutions[0].message_keys, messages.iter().map(|(_, message_key, _)| message_key.clone()).collect::<Vec<_>>());
+    let plaintexts: Vec<&str> = solutions[0].plaintexts.iter().map(|p| p.as_str()).collect();
+    assert_eq!(plaintexts, messages.iter().map(|&(_, _, plaintext)| plaintext).collect::<Vec<_>>());
+    assert!(Transmission::parse("RTJ WXCW NQBFK").is_err());
+    spec.positions = Some(vec![0, 0, 0]);
+    assert!(brute_force_indicators(&transmissions, &model, &spec, 1, &control).is_err());
+}
+
+#[test]
+fn m4_tests() {
+    let world = encrypt::world();
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let plaintext = "UBOOTSSHOULDGATHERNORTHOFTHECONVOYROUTEANDWAITFORFURTHERORDERSBEFOREATTACKING";
+    let key = encrypt::Key {
+        rotors: vec![0, 5, 7, encrypt::GREEK_ROTORS[0]], reflector: 3, rings: vec![2, 5, 0, 2],
+        positions: vec![5, 10, 3, 23], plugboard: encrypt::identity_plugboard(),
+    };
+    let ciphertext: String = encrypt::encrypt_key_unchecked(&world, &key, &encrypt::input_to_u8(plaintext)).iter().map(|&c| encrypt::chr(c)).collect();
+    let mut spec = SearchSpec::m4();
+    spec.rotors = vec![None, Some(5), Some(7)];
+    spec.rotor_pool = vec![0, 1];
+    spec.reflectors = vec![3];
+    spec.greek = vec![encrypt::GREEK_ROTORS[0]];
+    let stop = AtomicBool::new(false);
+    let control = Control::unlimited(&stop);
+    let solutions = brute_force(&ciphertext, &model, &spec, 1, &control).unwrap();
+    assert_eq!(solutions[0].plaintext, plaintext);
+    assert_eq!(solutions[0].key.rotors, key.rotors);
+    assert_eq!((solutions[0].key.positions[0], solutions[0].key.rings[3]), (3, 0));
+    spec.rings = Some(vec![0, 0, 0]);
+    assert!(brute_force(&ciphertext, &model, &spec, 1, &control).is_err());
+    assert!(brute_force_depth(&[ciphertext.as_str()], &model, &SearchSpec::m4(), 1, &control).is_err());
+    spec.rings = None;
+    spec.rotors = vec![None, Some(5), Some(5)];
+    assert_eq!(brute_force(&ciphertext, &model, &spec, 1, &control).err(), Some(Error::DuplicateRotor("VI".to_string())));
+    spec.rotors = vec![None, Some(5), Some(7)];
+    spec.greek = vec![10];
+    assert_eq!(brute_force(&ciphertext, &model, &spec, 1, &control).err(), Some(Error::InvalidRotor(10)));
+}
+
+#[test]
+fn random_key_tests() {
+    let mut rng = Rng::new(7);
+    for spec in [SearchSpec::new(), SearchSpec::m4()].iter() {
+        for pairs in [0, 10, 13].iter() {
+            let key = spec.random_key(*pairs, &mut rng).unwrap();
+            assert_eq!(key.rotors.len(), spec.key_len());
+            assert!(spec.reflectors.contains(&key.reflector));
+            assert_eq!((0..26).filter(|&c| key.plugboard[c] != c as u8).count(), 2 * pairs);
+        }
+    }
+    assert!(SearchSpec::new().random_key(14, &mut rng).is_err());
+    assert!(SearchSpec { rotor_pool: vec![0, 1], .. SearchSpec::new() }.random_key(10, &mut rng).is_err());
+}
+
+#[test]
+fn spec_check_tests() {
+    let model = crate::ngram::Language::English.ngrams(2).unwrap();
+    let stop = AtomicBool::new(false);
+    let control = Control::unlimited(&stop);
+    let mut plugboard = encrypt::identity_plugboard();
+    plugboard[0] = 1;
+    plugboard[1] = 0;
+    plugboard[2] = 1;
+    let invalid = vec![
+        SearchSpec { reflectors: vec![5], .. SearchSpec::new() },
+        SearchSpec { reflectors: Vec::new(), .. SearchSpec::new() },
+        SearchSpec { reflectors: vec![3], .. SearchSpec::new() },
+        SearchSpec { reflectors: vec![1], .. SearchSpec::m4() },
+        SearchSpec { rotor_pool: vec![0, 1, 2, 8], .. SearchSpec::new() },
+        SearchSpec { rotors: vec![None, None, Some(9)], .. SearchSpec::new() },
+        SearchSpec { rotors: vec![None, None], .. SearchSpec::new() },
+        SearchSpec { greek: vec![0], .. SearchSpec::m4() },
+        SearchSpec { rings: Some(vec![0, 0, 26]), .. SearchSpec::new() },
+        SearchSpec { plugboard, .. SearchSpec::new() },
+        SearchSpec { rotors: vec![None, Some(1), Some(2)], rotor_pool: vec![1, 2], .. SearchSpec::new() },
+    ];
+    for spec in invalid.iter() {
+        assert!(matches!(brute_force("ABCDEFGHIJ", &model, spec, 1, &control), Err(Error::Search(_))), "{:?}", spec);
+        assert!(matches!(bombe("ABCDEFGHIJ", "XYZ", None, spec, 1, &control), Err(Error::Search(_))), "{:?}", spec);
+    }
+    let spec = SearchSpec { reflectors: vec![7], .. SearchSpec::new() };
+    assert!(matches!(brute_force_depth(&["ABCDEFGHIJ"], &model, &spec, 1, &control), Err(Error::Search(_))));
+    // A known wheel order needs no pool.
+    let spec = SearchSpec { rotors: vec![Some(0), Some(1), Some(2)], rotor_pool: Vec::new(), .. SearchSpec::new() };
+    assert_eq!(spec.templates().len(), 1);
+    assert!(brute_force("ABCDEFGHIJ", &model, &spec, 1, &control).is_ok());
+    // The counts given in the help of --machine.
+    assert_eq!(SearchSpec::new().templates().len(), 60);
+    assert_eq!(SearchSpec::m4().templates().len(), 34944);
+}
+
+// Historical messages enciphered again without their plugboards, so that the searches
+// without steckers have to keep breaking them. Only the right rotor is searched to keep
+// the test short.
+#[test]
+fn historical_break_tests() {
+    let world = encrypt::world();
+    let model = crate::ngram::Language::German.ngrams(4).unwrap();
+    let samples = [
+        // The example of the 1930 operating instructions of the Enigma I, with reflector A.
+        (0, vec![2, 0, 1], vec![21, 12, 23], vec![0, 1, 11],
+         "FEINDLIQEINFANTERIEKOLONNEBEOBAQTETXANFANGSUEDAUSGANGBAERWALDEXENDEDREIKMOSTWAERTSNEUSTADT"),
+        // The first part of the Barbarossa message.
+        (1, vec![4, 3, 1], vec![11, 20, 1], vec![1, 11, 0],
+         "AUFKLXABTEILUNGXVONXKURTINOWAXKURTINOWAXNORDWESTLXSEBEZXSEBEZXUAFFLIEGERSTRASZERIQTUNGXDUBROWKIXDUBROWKIXOPOTSCHKAXOPOTSCHKAXUMXEINSAQTDREINULLXUHRANGETRETENXANGRIFFXINFXRGTX"),
+        // Its second part, with another message key.
+        (1, vec![4, 3, 1], vec![11, 20, 1], vec![11, 18, 3],
+         "DREIGEHTLANGSAMABERSIQERVORWAERTSXEINSSIEBENNULLSEQSXUHRXROEMXEINSXINFRGTXDREIXAUFFLIEGERSTRASZEMITANFANGXEINSSEQSXKMXKMXOSTWXKAMENECXK"),
+    ];
+    let stop = AtomicBool::new(false);
+    let control = Control::unlimited(&stop);
+    for &(reflector, ref rotors, ref rings, ref positions, plaintext) in samples.iter() {
+        let mut key = encrypt::Key::new(rotors.clone(), positions.clone(), rings.clone());
+        key.reflector = reflector;
+        let ciphertext: String = encrypt::encrypt_key_unchecked(&world, &key, &encrypt::input_to_u8(plaintext)).iter().map(|&c| encrypt::chr(c)).collect();
+        let mut spec = SearchSpec::new();
+        spec.rotors = vec![None, Some(rotors[1]), Some(rotors[2])];
+        spec.reflectors = vec![reflector];
+        let solutions = brute_force(&ciphertext, &model, &spec, 1, &control).unwrap();
+        assert_eq!(solutions[0].plaintext, plaintext);
+        assert_eq!(solutions[0].key.rotors, key.rotors);
+        assert!(solutions[0].confidence.broken);
+    }
+}
+
+#[test]
+fn confidence_tests() {
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let mut spec = SearchSpec::new();
+    let plaintext = encrypt::input_to_u8("THEQUICKBROWNFOXJUMPSOVERTHELAZYDOGANDTHENITRANSAWAYINTOTHEFOREST");
+    let random = encrypt::input_to_u8("QGUNEHMMANGATACQAPGOEOVDOSIBBYRNALEFAANFXKWPZJVQTRMCLUDHOYEBISGKF");
+    let english = confidence(&model, broken_z_score(&spec, 1), slice::from_ref(&plaintext));
+    assert!(english.broken && 0.04 < english.ic);
+    assert!(!confidence(&model, broken_z_score(&spec, 1), slice::from_ref(&random)).broken);
+    assert_eq!(confidence(&model, 0., &[]).z_score, 0.);
+    // Long texts, e.g. given to the score command, do not overflow the counts.
+    let long: Vec<u8> = (0..52000).map(|idx| (idx % 26) as u8).collect();
+    assert!((index_of_coincidence(long.iter().copied()) - 1999. / 51999.).abs() < 1e-9);
+    assert_eq!(index_of_coincidence(std::iter::once(0)), 0.);
+    spec.steckers = Some(Optimiser::HillClimb);
+    assert_eq!(broken_z_score(&spec, 4), 2. * BROKEN_Z_SCORE_STECKERS);
+}
+
+#[test]
+fn uneven_tests() {
+    let model = crate::ngram::Language::English.ngrams(4).unwrap();
+    let plaintext = encrypt::input_to_u8("THEREGIMENTWILLCROSSTHERIVERATDAWNNEARTHEOLDBRIDGEANDHOLDTHEROADS");
+    let random = encrypt::input_to_u8("QGUNEHMMANGATACQAPGOEOVDOSIBBYRNALEFAANFXKWPZJVQTRMCLUDHOYEBISGKF");
+    assert!(!reads_unevenly(&model, &random));
+    // A middle rotor stepping at the wrong letter garbles the letters up to the next
+    // turnover, or up to the end of the message.
+    let garbled = |start: usize, end: usize| -> Vec<u8> {
+        plaintext.iter().zip(random.iter()).enumerate().map(|(idx, (&p, &r))| if start <= idx && idx < end { r } else { p }).collect()
+    };
+    assert!(reads_unevenly(&model, &garbled(20, 40)));
+    assert!(reads_unevenly(&model, &garbled(0, 12)));
+    assert!(reads_unevenly(&model, &garbled(50, plaintext.len())));
+}
+
+#[test]
+fn bombe_tests() {
+    let world = encrypt::world();
+    let plaintext = encrypt::input_to_u8("WETTERVORHERSAGEBISKAYAREGENUNDSTURMAUSWESTENSICHTWEITEGERING");
+    let crib = "WETTERVORHERSAGE";
+    let mut key = encrypt::Key::new(vec![3, 0, 2], vec![7, 11, 10], vec![0, 0, 0]);
+    key.plugboard = encrypt::parse_plugboard("AQ BT CX DM FK GW").unwrap();
+    let ciphertext: String = encrypt::encrypt_key_unchecked(&world, &key, &plaintext).iter().map(|&c| encrypt::chr(c)).collect();
+    let mut spec = SearchSpec::new();
+    spec.rotors = vec![None, Some(0), Some(2)];
+    let stop = AtomicBool::new(false);
+    let control = Control::unlimited(&stop);
+    let stops = bombe(&ciphertext, crib, Some(0), &spec, 1, &control).unwrap();
+    let found = stops.iter().find(|s| s.key.rotors == key.rotors && s.key.positions == key.positions).unwrap();
+    assert!((0..26).all(|c| found.key.plugboard[c] == c as u8 || found.key.plugboard[c] == key.plugboard[c]));
+    assert!(found.plaintext.starts_with(crib));
+    assert!(stops.len() < 10);
+    let impossible = (0..plaintext.len() - crib.len()).find(|offset| !crib_offsets(&encrypt::input_to_u8(&ciphertext), &encrypt::input_to_u8(crib)).contains(offset)).unwrap();
+    assert!(bombe(&ciphertext, crib, Some(impossible), &spec, 1, &control).is_err());
+    // With its ring at A the right rotor would reach its turnover within the crib, the
+    // ring of the stop is turned so that the crib can be read.
+    let key = encrypt::Key { rings: vec![10, 0, 0], positions: vec![7, 11, 13], .. key };
+    let ciphertext: String = encrypt::encrypt_key_unchecked(&world, &key, &plaintext).iter().map(|&c| encrypt::chr(c)).collect();
+    let stops = bombe(&ciphertext, crib, Some(0), &spec, 1, &control).unwrap();
+    let found = stops.iter().find(|s| s.key.rotors == key.rotors && s.plaintext.starts_with(crib)).unwrap();
+    assert_eq!((found.key.rings[0], found.key.positions[2]), (7, 10));
+    // A short crib stops almost everywhere, the search ends once spec.candidates stops
+    // are found and they are the first ones of a longer search.
+    spec.rotors = vec![Some(3), Some(0), Some(2)];
+    spec.candidates = 3;
+    let few = bombe(&ciphertext, "WET", Some(0), &spec, 1, &control).unwrap();
+    spec.candidates = 5;
+    let more = bombe(&ciphertext, "WET", Some(0), &spec, 1, &control).unwrap();
+    assert_eq!((few.len(), more.len()), (3, 5));
+    assert!(few.iter().zip(more.iter()).all(|(a, b)| a.key == b.key && a.plaintext == b.plaintext));
+}
@@ -1,23 +1,43 @@
-use std::iter;
+use std::cmp;
+use std::fmt;
+use std::slice;
+use crate::error::Error;
 
 static DOUBLE_STEPPING : bool = true;
 
-static ROTORS : [&'static str; 5] = [
+// Rotors I to V are the army ones, VI to VIII were only issued to the navy and Beta and
+// Gamma are the greek wheels of the M4, which sit left of the three other rotors.
+#[allow(clippy::redundant_static_lifetimes)]
+static ROTORS : [&'static str; 10] = [
     "EKMFLGDQVZNTOWYHXUSPAIBRCJ",
     "AJDKSIRUXBLHWTMCQGZNPYFVOE",
     "BDFHJLCPRTXVZNYEIWGAKMUSQO",
     "ESOVPZJAYQUIRHXLNFTGKDCMWB",
     "VZBRGITYUPSDNHLXAWMJQOFECK",
+    "JPGVOUMFYQBENHZRDKASXLICTW",
+    "NZJHGRCXMYSWBOUFAIVLPEKQDT",
+    "FKQHTLXOCBJSPDZRAMEWNIUYGV",
+    "LEYJVCNIXWPBQMDRTAKZGFUHOS",
+    "FSOKANUERHMBTIYCWLQPZXVGJD",
 ];
 
-static TURNOVERS : [char; 5] = [
-    'Q',
-    'E',
-    'V',
-    'J',
-    'Z',
+// The positions at which each rotor engages the one on its left. The naval rotors have
+// two notches and the greek wheels, which never move, none.
+#[allow(clippy::redundant_static_lifetimes)]
+static TURNOVERS : [&'static str; 10] = [
+    "Q",
+    "E",
+    "V",
+    "J",
+    "Z",
+    "ZM",
+    "ZM",
+    "ZM",
+    "",
+    "",
 ];
 
+#[allow(clippy::redundant_static_lifetimes)]
 static REFLECTORS : [&'static str; 5] = [
     "EJMZALYXVBWFCRQUONTSPIKHGD",
     "YRUHQSLDPXNGOKMIEBFZCWVJAT",
@@ -26,10 +46,21 @@ static REFLECTORS : [&'static str; 5] = [
     "RDOBJNTKVEHMLFCWZAXGYIPSUQ",
 ];
 
+static ROTOR_NAMES : [&str; 10] = [ "I", "II", "III", "IV", "V", "VI", "VII", "VIII", "Beta", "Gamma" ];
+
+pub const ARMY_ROTORS: u8 = 5;
+pub const NAVAL_ROTORS: u8 = 8;
+pub const GREEK_ROTORS: [u8; 2] = [8, 9];
+pub const THIN_REFLECTORS: [u8; 2] = [3, 4];
+pub const REFLECTOR_COUNT: u8 = 5;
+
+static REFLECTOR_NAMES : [&str; 5] = [ "A", "B", "C", "B-thin", "C-thin" ];
+
 struct Rotor {
     sigma: Vec<u8>,
     sigma_inv: Vec<u8>,
-    turnover: u8,
+    // Bit p is set when the rotor has a notch at position p.
+    turnovers: u32,
 }
 
 pub struct World {
@@ -46,105 +77,218 @@ struct Config<'a> {
     rings: &'a Vec<u8>,
 }
 
+#[allow(clippy::needless_return)]
 fn add26(x : u8, y : u8) -> u8 {
     let res = x + y;
     return if 26 <= res { res - 26 } else { res };
 }
+#[allow(clippy::needless_return)]
 fn sub26(x : u8, y : u8) -> u8 { return add26(x, 26 - y); }
 
+#[allow(clippy::needless_return, clippy::ptr_arg)]
 fn inv_permutation(sigma : &Vec<u8>) -> Vec<u8> {
-    let mut sigma_inv: Vec<u8> = iter::repeat(0u8).take(sigma.len()).collect();
+    let mut sigma_inv = vec![0u8; sigma.len()];
     for (idx, &sigma_idx) in sigma.iter().enumerate() {
         sigma_inv[sigma_idx as usize] = idx as u8;
     }
     return sigma_inv;
 }
 
-fn step(state : &mut Vec<u8>, config : &Config) {
+// Steps the rotors, state[0] being the rightmost one. at_turnover(idx, position) tells
+// whether rotor idx engages the next rotor when its window shows position.
+#[allow(clippy::needless_range_loop)]
+fn step_with<F: Fn(usize, u8) -> bool>(state : &mut [u8], at_turnover : F) {
     if DOUBLE_STEPPING {
         let mut last_gray = None;
-        for idx in (0us .. config.rotors.len() - 1) {
-            if state[idx] == config.rotors[idx].turnover { last_gray = Some(idx); }
+        for idx in 0..state.len() - 1 {
+            if at_turnover(idx, state[idx]) { last_gray = Some(idx); }
         }
         match last_gray {
             None => state[0] = add26(state[0], 1),
             Some(last_gray) => {
-                for idx in (0us .. last_gray + 2) {
+                for idx in 0..last_gray + 2 {
                     state[idx] = add26(state[idx], 1);
                 }
             }
         }
     }
     else {
-        for (idx, rotor) in config.rotors.iter().enumerate() {
-            let should_break = state[idx] != rotor.turnover;
+        for idx in 0..state.len() {
+            let should_break = !at_turnover(idx, state[idx]);
             state[idx] = add26(state[idx], 1);
             if should_break { break; }
         }
     }
 }
 
-fn encrypt_one(value : u8, state : &mut Vec<u8>, config : &Config) -> u8 {
+fn at_turnover(turnovers: u32, position: u8) -> bool {
+    (turnovers >> position) & 1 == 1
+}
+
+// Only the three rightmost rotors move, the greek wheel of a four rotor machine stays put.
+#[allow(clippy::ptr_arg)]
+fn step(state : &mut Vec<u8>, config : &Config) {
+    let moving = cmp::min(state.len(), 3);
+    step_with(&mut state[..moving], |idx, position| at_turnover(config.rotors[idx].turnovers, position))
+}
+
+// The path of a letter through the machine, as recorded by encrypt_one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    pub input: u8,
+    // The positions shown in the windows once the rotors have stepped, leftmost first.
+    pub positions: Vec<u8>,
+    // The letter after the plugboard, going in.
+    pub plugboard: u8,
+    // The letter after each rotor on the way to the reflector, rightmost rotor first.
+    pub forward: Vec<u8>,
+    pub reflector: u8,
+    // The letter after each rotor on the way back, leftmost rotor first.
+    pub backward: Vec<u8>,
+    // The lamp lit, i.e. the letter after the plugboard going out.
+    pub output: u8,
+}
+
+// When trace is given, every substitution of the letter is recorded in it.
+#[allow(clippy::needless_return)]
+fn encrypt_one(value : u8, state : &mut Vec<u8>, config : &Config, mut trace: Option<&mut Trace>) -> u8 {
+    let input = value;
     step(state, config);
     let mut value = config.plugboard[value as usize];
+    let plugboard = value;
+    let mut forward = Vec::new();
     for (idx, rotor) in config.rotors.iter().enumerate() {
         value = add26(sub26(value, config.rings[idx]), state[idx]);
         value = rotor.sigma[value as usize];
         value = sub26(add26(value, config.rings[idx]), state[idx]);
+        if trace.is_some() { forward.push(value); }
     }
     value = config.sigma_reflector[value as usize];
+    let reflector = value;
+    let mut backward = Vec::new();
     for (idx, rotor) in config.rotors.iter().enumerate().rev() {
         value = add26(sub26(value, config.rings[idx]), state[idx]);
         value = rotor.sigma_inv[value as usize];
         value = sub26(add26(value, config.rings[idx]), state[idx]);
+        if trace.is_some() { backward.push(value); }
     }
-    return config.plugboard_inv[value as usize];
+    let output = config.plugboard_inv[value as usize];
+    if let Some(ref mut trace) = trace {
+        **trace = Trace {
+            input, positions: state.iter().rev().copied().collect(), plugboard,
+            forward, reflector, backward, output,
+        };
+    }
+    return output;
 }
 
-fn ord(c : char) -> Option<u8> {
-    if 'a' <= c && c <= 'z' {
-        return Some(c as u8 - 'a' as u8);
+#[allow(clippy::needless_return)]
+pub fn ord(c : char) -> Option<u8> {
+    if c.is_ascii_lowercase() {
+        return Some(c as u8 - b'a');
     }
-    else if 'A' <= c && c <= 'Z' {
-        return Some(c as u8 - 'A' as u8);
+    else if c.is_ascii_uppercase() {
+        return Some(c as u8 - b'A');
     }
     else { return None; }
 }
 
-fn chr(o : u8) -> char {
+#[allow(clippy::needless_return)]
+pub fn chr(o : u8) -> char {
     if o < 26 {
-        return (o + 'A' as u8) as char;
+        return (o + b'A') as char;
     }
     return '?';
 }
 
+#[allow(clippy::needless_return)]
 fn str_to_vec8(input : &str) -> Vec<u8> {
-    return input.chars().map(|c| c as u8 - 'A' as u8).collect();
+    return input.chars().map(|c| c as u8 - b'A').collect();
 }
 
-fn str_to_vec8_rev(input : &str) -> Vec<u8> {
-    return input.chars().map(|c| c as u8 - 'A' as u8).rev().collect();
+// Parses a key, ring setting or other fixed number of letters, in either case.
+pub fn parse_letters(letters: &str, len: usize, what: &'static str) -> Result<Vec<u8>, Error> {
+    if let Some(letter) = letters.chars().find(|&c| ord(c).is_none()) {
+        return Err(Error::InvalidLetter { what, letter });
+    }
+    if letters.chars().count() != len {
+        return Err(Error::Length { what, value: letters.to_string(), expected: len });
+    }
+    Ok(input_to_u8(letters))
+}
+
+// Checks that a wheel order only uses existing rotors, each of them once.
+pub fn check_rotors(rotors: &[u8]) -> Result<(), Error> {
+    for (idx, &rotor) in rotors.iter().enumerate() {
+        if ROTORS.len() <= rotor as usize {
+            return Err(Error::InvalidRotor(rotor));
+        }
+        if rotors[..idx].contains(&rotor) {
+            return Err(Error::DuplicateRotor(rotor_name(rotor).to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Letters given as indices, as for the functions below, should be between 0 for A and 25
+// for Z.
+pub(crate) fn check_input(input: &[u8]) -> Result<(), Error> {
+    match input.iter().find(|&&c| 26 <= c) {
+        Some(&c) => Err(Error::LetterIndex(c)),
+        None => Ok(()),
+    }
+}
+
+// Checks everything but the plugboard of a key, see Key::check.
+fn check_setting(rotors: &[u8], reflector: u8, rings: &[u8], positions: &[u8]) -> Result<(), Error> {
+    check_rotors(rotors)?;
+    let n = rotors.len();
+    if n != 3 && n != 4 {
+        return Err(Error::Key(format!("there should be 3 rotors, or 4 with a greek wheel, not {}", n)));
+    }
+    if rings.len() != n || positions.len() != n || rings.iter().chain(positions.iter()).any(|&c| 26 <= c) {
+        return Err(Error::Key(format!("the rings and positions should be given for {} rotors", n)));
+    }
+    for (idx, &rotor) in rotors.iter().enumerate() {
+        if GREEK_ROTORS.contains(&rotor) != (idx == 3) {
+            return Err(Error::Key(format!("{} cannot be used in slot {} from the right", rotor_name(rotor), idx + 1)));
+        }
+    }
+    if REFLECTOR_NAMES.len() <= reflector as usize {
+        return Err(Error::Key(format!("there is no reflector with index {}", reflector)));
+    }
+    if THIN_REFLECTORS.contains(&reflector) != (n == 4) {
+        return Err(Error::Key(format!("reflector {} cannot be used with {} rotors", reflector_name(reflector), n)));
+    }
+    Ok(())
 }
 
+#[allow(clippy::ptr_arg)]
 fn create_config<'a>(world: &'a World, rotor_config: &Vec<u8>, rings: &'a Vec<u8>) -> Config<'a> {
+    create_config_with(world, rotor_config, rings, 1, &world.id)
+}
+
+// Plugboards are involutions so they are their own inverse.
+fn create_config_with<'a>(world: &'a World, rotor_config: &[u8], rings: &'a Vec<u8>, reflector: u8, plugboard: &'a Vec<u8>) -> Config<'a> {
     Config {
         rotors: rotor_config.iter().map(|&x| &world.rotors[x as usize]).collect(),
-        sigma_reflector: &world.reflectors[1],
-        plugboard: &world.id,
-        plugboard_inv: &world.id,
-        rings: rings,
+        sigma_reflector: &world.reflectors[reflector as usize],
+        plugboard,
+        plugboard_inv: plugboard,
+        rings,
     }
 }
 
 // world() is used to convert ROTORS, TURNOVERS, and REFLECTORS to some appropriate types.
+#[allow(clippy::redundant_field_names)]
 pub fn world() -> World {
     let mut rotors = Vec::new();
     // This should be done with an indexed map
-    for rotor_idx in (0us .. ROTORS.len()) {
+    for rotor_idx in 0..ROTORS.len() {
         let sigma = str_to_vec8(ROTORS[rotor_idx]);
-        let turnover = TURNOVERS[rotor_idx] as u8 - 'A' as u8;
+        let turnovers = str_to_vec8(TURNOVERS[rotor_idx]).iter().fold(0, |acc, &p| acc | 1 << p);
         let sigma_inv = inv_permutation(&sigma);
-        let rotor = Rotor { sigma: sigma, sigma_inv: sigma_inv, turnover: turnover };
+        let rotor = Rotor { sigma: sigma, sigma_inv: sigma_inv, turnovers: turnovers };
         rotors.push(rotor);
     }
     let id = (0u8 .. 26).collect();
@@ -153,28 +297,510 @@ pub fn world() -> World {
 }
 
 pub fn input_to_u8(input: &str) -> Vec<u8> {
-    input.chars().filter_map(|c| ord(c)).collect()
+    input.chars().filter_map(ord).collect()
 }
 
-pub fn encrypt_u8(world: &World, input: &Vec<u8>, rotor_config: &Vec<u8>, key: &Vec<u8>, rings: &Vec<u8>) -> Vec<u8> {
+// Encrypts input on a machine with reflector B and no plugboard, after checking the
+// setting and the input.
+#[allow(clippy::ptr_arg)]
+pub fn encrypt_u8(world: &World, input: &Vec<u8>, rotor_config: &Vec<u8>, key: &Vec<u8>, rings: &Vec<u8>) -> Result<Vec<u8>, Error> {
+    check_setting(rotor_config, 1, rings, key)?;
+    check_input(input)?;
     let config = create_config(world, rotor_config, rings);
     // Is this really the best way to reverse a Vec ?
-    let mut state = key.iter().rev().map(|&x| x).collect();
-    input.iter().map(|&c| encrypt_one(c, &mut state, &config)).collect()
+    let mut state = key.iter().rev().copied().collect();
+    Ok(input.iter().map(|&c| encrypt_one(c, &mut state, &config, None)).collect())
 }
 
-pub fn encrypt(input : &str, rotor_config : &Vec<u8>, key : &str, rings : &str) -> String {
+// As encrypt_u8 with the key and rings given as letters, in the same order as for
+// encrypt_u8, checking them and the wheel order first. Characters other than letters
+// are dropped.
+#[allow(clippy::needless_return)]
+pub fn encrypt(input : &str, rotor_config : &Vec<u8>, key : &str, rings : &str) -> Result<String, Error> {
+    check_rotors(rotor_config)?;
+    let rings = parse_letters(rings, rotor_config.len(), "rings")?;
+    let positions = parse_letters(key, rotor_config.len(), "key")?;
+    check_setting(rotor_config, 1, &rings, &positions)?;
+    let mut state: Vec<u8> = positions.into_iter().rev().collect();
     let world = world();
-    let rings = str_to_vec8(rings);
     let config = create_config(&world, rotor_config, &rings);
-    let mut state = str_to_vec8_rev(key);
-    return input.chars().filter_map(|c|
-        ord(c).map(|c| chr(encrypt_one(c, &mut state, &config)))).collect();
+    return Ok(input.chars().filter_map(|c|
+        ord(c).map(|c| chr(encrypt_one(c, &mut state, &config, None)))).collect());
+}
+
+// A complete machine setting. As for encrypt_u8, rotors and rings start from the
+// rightmost rotor whereas positions start from the leftmost one, i.e. are in the order
+// shown in the windows. The plugboard is given as the permutation it applies. A four
+// rotor key, for the M4, has its greek wheel last in rotors and rings and first in
+// positions, it is used with one of the thin reflectors.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Key {
+    pub rotors: Vec<u8>,
+    pub reflector: u8,
+    pub rings: Vec<u8>,
+    pub positions: Vec<u8>,
+    pub plugboard: Vec<u8>,
+}
+
+impl Key {
+    // The setting used by encrypt_u8: reflector B and no plugboard.
+    pub fn new(rotors: Vec<u8>, positions: Vec<u8>, rings: Vec<u8>) -> Key {
+        Key { rotors, reflector: 1, rings, positions, plugboard: identity_plugboard() }
+    }
+
+    // Checks that the key can be set on a machine: three distinct rotors or four with a
+    // greek wheel and a thin reflector, rings and positions for each of them, and a
+    // plugboard made of pairs.
+    pub fn check(&self) -> Result<(), Error> {
+        check_setting(&self.rotors, self.reflector, &self.rings, &self.positions)?;
+        if self.plugboard.len() != 26 || (0..26).any(|c| 26 <= self.plugboard[c] || self.plugboard[self.plugboard[c] as usize] != c as u8) {
+            return Err(Error::Plugboard("the plugboard should consist of pairs of letters".to_string()));
+        }
+        Ok(())
+    }
+
+    // The key without the start positions, as given by a key sheet when the operator
+    // chooses the start positions of each message.
+    pub fn setting(&self) -> String {
+        let rotors: Vec<&str> = self.rotors.iter().rev().map(|&r| rotor_name(r)).collect();
+        let rings: String = self.rings.iter().rev().map(|&r| chr(r)).collect();
+        let plugboard = plugboard_pairs(&self.plugboard);
+        format!("reflector {} rotors {} rings {} plugboard {}", reflector_name(self.reflector), rotors.join("-"), rings,
+                if plugboard.is_empty() { "-".to_string() } else { plugboard })
+    }
+}
+
+// The key is displayed with all the components going from left to right.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rotors: Vec<&str> = self.rotors.iter().rev().map(|&r| rotor_name(r)).collect();
+        let rings: String = self.rings.iter().rev().map(|&r| chr(r)).collect();
+        let positions: String = self.positions.iter().map(|&p| chr(p)).collect();
+        let plugboard = plugboard_pairs(&self.plugboard);
+        write!(f, "reflector {} rotors {} rings {} positions {} plugboard {}",
+               reflector_name(self.reflector), rotors.join("-"), rings, positions,
+               if plugboard.is_empty() { "-".to_string() } else { plugboard })
+    }
+}
+
+pub fn rotor_name(rotor: u8) -> &'static str { ROTOR_NAMES[rotor as usize] }
+
+pub fn rotor_from_name(name: &str) -> Option<u8> {
+    ROTOR_NAMES.iter().position(|&n| n.eq_ignore_ascii_case(name)).map(|idx| idx as u8)
+}
+
+// The windows from which the rotor engages the next one when stepping, e.g. Q for rotor I.
+pub fn rotor_turnovers(rotor: u8) -> Vec<u8> { str_to_vec8(TURNOVERS[rotor as usize]) }
+
+pub fn reflector_name(reflector: u8) -> &'static str { REFLECTOR_NAMES[reflector as usize] }
+
+pub fn reflector_from_name(name: &str) -> Option<u8> {
+    REFLECTOR_NAMES.iter().position(|&n| n.eq_ignore_ascii_case(name)).map(|idx| idx as u8)
+}
+
+pub fn identity_plugboard() -> Vec<u8> {
+    (0u8 .. 26).collect()
+}
+
+// Parses stecker pairs such as "AV BS CG", returning the corresponding permutation.
+pub fn parse_plugboard(pairs: &str) -> Result<Vec<u8>, Error> {
+    let mut plugboard = identity_plugboard();
+    for pair in pairs.split_whitespace() {
+        let letters: Vec<u8> = pair.chars().filter_map(ord).collect();
+        if letters.len() != 2 || pair.chars().count() != 2 || letters[0] == letters[1] {
+            return Err(Error::Plugboard(format!("'{}' is not a pair of different letters", pair)));
+        }
+        let (a, b) = (letters[0] as usize, letters[1] as usize);
+        if plugboard[a] != a as u8 || plugboard[b] != b as u8 {
+            return Err(Error::Plugboard(format!("letter used twice in pair '{}'", pair)));
+        }
+        plugboard[a] = b as u8;
+        plugboard[b] = a as u8;
+    }
+    Ok(plugboard)
+}
+
+pub fn plugboard_pairs(plugboard: &[u8]) -> String {
+    let pairs: Vec<String> = plugboard.iter().enumerate()
+        .filter(|&(a, &b)| (a as u8) < b)
+        .map(|(a, &b)| format!("{}{}", chr(a as u8), chr(b))).collect();
+    pairs.join(" ")
+}
+
+pub fn encrypt_key(world: &World, key: &Key, input: &[u8]) -> Result<Vec<u8>, Error> {
+    key.check()?;
+    check_input(input)?;
+    Ok(encrypt_key_unchecked(world, key, input))
+}
+
+// As encrypt_key for a key and an input known to be valid, e.g. those of the searches.
+pub(crate) fn encrypt_key_unchecked(world: &World, key: &Key, input: &[u8]) -> Vec<u8> {
+    let config = create_config_with(world, &key.rotors, &key.rings, key.reflector, &key.plugboard);
+    let mut state = key.positions.iter().rev().copied().collect();
+    input.iter().map(|&c| encrypt_one(c, &mut state, &config, None)).collect()
+}
+
+// Enciphers the letters of the lines of text as one message, returning the lines of the
+// output so that the layout of the message is kept.
+pub fn encrypt_lines(world: &World, key: &Key, text: &str) -> Result<Vec<String>, Error> {
+    let lines: Vec<Vec<u8>> = text.lines().map(input_to_u8).collect();
+    let output = encrypt_key(world, key, &lines.concat())?;
+    let mut output = output.iter().map(|&c| chr(c));
+    Ok(lines.iter().map(|line| output.by_ref().take(line.len()).collect()).collect())
+}
+
+// A machine being operated key by key, e.g. by the interactive command. The positions of
+// the previous key presses are kept so that they can be undone, as an operator would by
+// turning the rotors back.
+pub struct Machine {
+    world: World,
+    key: Key,
+    // The positions before each key press, rightmost first as for encrypt_one.
+    state: Vec<u8>,
+    history: Vec<Vec<u8>>,
+}
+
+impl Machine {
+    pub fn new(key: Key) -> Result<Machine, Error> {
+        key.check()?;
+        let state = key.positions.iter().rev().copied().collect();
+        Ok(Machine { world: world(), key, state, history: Vec::new() })
+    }
+
+    // The current setting, its positions being the ones shown in the windows.
+    pub fn key(&self) -> Key {
+        Key { positions: self.state.iter().rev().copied().collect(), .. self.key.clone() }
+    }
+
+    // Steps the rotors and returns the lamp lit by the key, letter being from 0 for A to
+    // 25 for Z.
+    pub fn press(&mut self, letter: u8) -> Result<u8, Error> {
+        check_input(&[letter])?;
+        self.history.push(self.state.clone());
+        let config = create_config_with(&self.world, &self.key.rotors, &self.key.rings, self.key.reflector, &self.key.plugboard);
+        Ok(encrypt_one(letter, &mut self.state, &config, None))
+    }
+
+    // Turns the rotors back to where they were before the last key press, returning false
+    // when there is none.
+    pub fn backspace(&mut self) -> bool {
+        match self.history.pop() {
+            Some(state) => { self.state = state; true },
+            None => false,
+        }
+    }
+
+    // Changes the setting of the machine, e.g. its plugboard, the previous key presses
+    // being forgotten.
+    pub fn set_key(&mut self, key: Key) -> Result<(), Error> {
+        key.check()?;
+        self.state = key.positions.iter().rev().copied().collect();
+        self.key = key;
+        self.history.clear();
+        Ok(())
+    }
+}
+
+// As encrypt_key, recording the path of each letter through the machine.
+pub fn trace_key(world: &World, key: &Key, input: &[u8]) -> Result<Vec<Trace>, Error> {
+    key.check()?;
+    check_input(input)?;
+    let config = create_config_with(world, &key.rotors, &key.rings, key.reflector, &key.plugboard);
+    let mut state = key.positions.iter().rev().copied().collect();
+    Ok(input.iter().map(|&c| {
+        let mut trace = Trace { input: c, positions: Vec::new(), plugboard: c, forward: Vec::new(), reflector: c, backward: Vec::new(), output: c };
+        encrypt_one(c, &mut state, &config, Some(&mut trace));
+        trace
+    }).collect())
+}
+
+// Precomputed lookup tables used by the search, so that decrypting a candidate does not
+// require any allocation. forward[r][o][c] is the output of rotor r for input c when the
+// rotor position minus its ring setting is o, backward[r][o] is its inverse.
+pub struct Tables {
+    forward: Vec<[[u8; 26]; 26]>,
+    backward: Vec<[[u8; 26]; 26]>,
+    turnovers: Vec<u32>,
+    reflectors: Vec<[u8; 26]>,
+}
+
+fn array26(v: &[u8]) -> [u8; 26] {
+    let mut res = [0u8; 26];
+    res.copy_from_slice(v);
+    res
+}
+
+pub fn tables(world: &World) -> Tables {
+    let mut forward = Vec::new();
+    let mut backward = Vec::new();
+    for rotor in world.rotors.iter() {
+        let mut fwd = [[0u8; 26]; 26];
+        let mut bwd = [[0u8; 26]; 26];
+        for offset in 0..26u8 {
+            for c in 0..26u8 {
+                fwd[offset as usize][c as usize] = sub26(rotor.sigma[add26(c, offset) as usize], offset);
+                bwd[offset as usize][c as usize] = sub26(rotor.sigma_inv[add26(c, offset) as usize], offset);
+            }
+        }
+        forward.push(fwd);
+        backward.push(bwd);
+    }
+    Tables {
+        forward,
+        backward,
+        turnovers: world.rotors.iter().map(|rotor| rotor.turnovers).collect(),
+        reflectors: world.reflectors.iter().map(|sigma| array26(sigma)).collect(),
+    }
+}
+
+// The reflector of key, combined with the greek wheel for a four rotor key. As the greek
+// wheel never moves, they act together as a fixed reflector.
+fn reflector_table(tables: &Tables, key: &Key) -> [u8; 26] {
+    let reflector = tables.reflectors[key.reflector as usize];
+    if key.rotors.len() < 4 { return reflector; }
+    let offset = sub26(key.positions[0], key.rings[3]) as usize;
+    let greek = key.rotors[3] as usize;
+    let (fwd, bwd) = (&tables.forward[greek][offset], &tables.backward[greek][offset]);
+    let mut res = [0u8; 26];
+    for c in 0..26 {
+        res[c] = bwd[reflector[fwd[c] as usize] as usize];
+    }
+    res
+}
+
+// The positions of the three rotors that move, rightmost first as for encrypt_one.
+fn moving_state(key: &Key) -> [u8; 3] {
+    let n = key.positions.len();
+    [key.positions[n - 1], key.positions[n - 2], key.positions[n - 3]]
+}
+
+// Combined table for the middle rotor, left rotor and reflector, state being in the
+// same order as for encrypt_one.
+fn inner_table(tables: &Tables, rotors: &[usize; 3], reflector: &[u8; 26], rings: &[u8; 3], state: &[u8; 3]) -> [u8; 26] {
+    let offset1 = sub26(state[1], rings[1]) as usize;
+    let offset2 = sub26(state[2], rings[2]) as usize;
+    let (fwd1, bwd1) = (&tables.forward[rotors[1]][offset1], &tables.backward[rotors[1]][offset1]);
+    let (fwd2, bwd2) = (&tables.forward[rotors[2]][offset2], &tables.backward[rotors[2]][offset2]);
+    let mut inner = [0u8; 26];
+    for c in 0..26 {
+        let value = reflector[fwd2[fwd1[c] as usize] as usize];
+        inner[c] = bwd1[bwd2[value as usize] as usize];
+    }
+    inner
+}
+
+// Iterator over the encryption of input. The inner table only gets recomputed when the
+// middle or left rotors move.
+pub struct Letters<'a> {
+    tables: &'a Tables,
+    input: &'a [u8],
+    rotors: [usize; 3],
+    reflector: [u8; 26],
+    plugboard: [u8; 26],
+    rings: [u8; 3],
+    state: [u8; 3],
+    inner: [u8; 26],
+    inner_state: Option<(u8, u8)>,
+}
+
+impl<'a> Iterator for Letters<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let (&value, rest) = self.input.split_first()?;
+        self.input = rest;
+        let t = self.tables;
+        let rotors = self.rotors;
+        step_with(&mut self.state, |idx, position| at_turnover(t.turnovers[rotors[idx]], position));
+        if self.inner_state != Some((self.state[1], self.state[2])) {
+            self.inner = inner_table(t, &self.rotors, &self.reflector, &self.rings, &self.state);
+            self.inner_state = Some((self.state[1], self.state[2]));
+        }
+        let offset0 = sub26(self.state[0], self.rings[0]) as usize;
+        let value = t.forward[rotors[0]][offset0][self.plugboard[value as usize] as usize];
+        let value = self.inner[value as usize];
+        Some(self.plugboard[t.backward[rotors[0]][offset0][value as usize] as usize])
+    }
 }
 
+fn rotor_array(rotor_config: &[u8]) -> [usize; 3] {
+    [rotor_config[0] as usize, rotor_config[1] as usize, rotor_config[2] as usize]
+}
+
+// Same as encrypt_key but using the precomputed tables and without allocating.
+pub fn encrypt_iter<'a>(tables: &'a Tables, key: &Key, input: &'a [u8]) -> Result<Letters<'a>, Error> {
+    key.check()?;
+    check_input(input)?;
+    Ok(encrypt_iter_unchecked(tables, key, input))
+}
+
+// As encrypt_iter for a key and an input known to be valid.
+pub(crate) fn encrypt_iter_unchecked<'a>(tables: &'a Tables, key: &Key, input: &'a [u8]) -> Letters<'a> {
+    Letters {
+        tables,
+        input,
+        rotors: rotor_array(&key.rotors),
+        reflector: reflector_table(tables, key),
+        plugboard: array26(&key.plugboard),
+        rings: [key.rings[0], key.rings[1], key.rings[2]],
+        state: moving_state(key),
+        inner: [0u8; 26],
+        inner_state: None,
+    }
+}
+
+// The full scrambler for each of the 26^3 rotor positions of a wheel order, reflector,
+// ring setting and plugboard, together with the position reached after stepping. Positions are indexed
+// as the keys, i.e. with the leftmost rotor being the most significant digit. This
+// is used when trying all the start positions for the same wheel order so that each
+// letter only costs two lookups. For a four rotor key only the positions of the three
+// rotors that move are indexed, the greek wheel keeping the position of the key.
+pub struct Scramblers {
+    next: Vec<u16>,
+    scramblers: Vec<[u8; 26]>,
+}
+
+pub fn key_index(key: &[u8]) -> usize {
+    key[0] as usize * 676 + key[1] as usize * 26 + key[2] as usize
+}
+
+pub fn index_key(index: usize) -> [u8; 3] {
+    [(index / 676) as u8, (index / 26 % 26) as u8, (index % 26) as u8]
+}
+
+// The positions of key are not used, except for the greek wheel.
+pub fn scramblers(tables: &Tables, key: &Key) -> Result<Scramblers, Error> {
+    key.check()?;
+    Ok(scramblers_unchecked(tables, key))
+}
+
+// As scramblers for a key known to be valid.
+pub(crate) fn scramblers_unchecked(tables: &Tables, key: &Key) -> Scramblers {
+    let rotors = rotor_array(&key.rotors);
+    let rings = [key.rings[0], key.rings[1], key.rings[2]];
+    let reflector = reflector_table(tables, key);
+    let plugboard = &key.plugboard;
+    let mut next = Vec::with_capacity(26 * 26 * 26);
+    let mut scramblers = Vec::with_capacity(26 * 26 * 26);
+    for left in 0..26u8 {
+        for middle in 0..26u8 {
+            let inner = inner_table(tables, &rotors, &reflector, &rings, &[0, middle, left]);
+            for right in 0..26u8 {
+                let offset0 = sub26(right, rings[0]) as usize;
+                let (fwd0, bwd0) = (&tables.forward[rotors[0]][offset0], &tables.backward[rotors[0]][offset0]);
+                let mut scrambler = [0u8; 26];
+                for c in 0..26 {
+                    let value = inner[fwd0[plugboard[c] as usize] as usize];
+                    scrambler[c] = plugboard[bwd0[value as usize] as usize];
+                }
+                scramblers.push(scrambler);
+                let mut state = [right, middle, left];
+                step_with(&mut state, |idx, position| at_turnover(tables.turnovers[rotors[idx]], position));
+                next.push(key_index(&[state[2], state[1], state[0]]) as u16);
+            }
+        }
+    }
+    Scramblers { next, scramblers }
+}
+
+impl Scramblers {
+    // Iterator over the encryption of input starting from key.
+    pub fn encrypt_iter<'a>(&'a self, input: &'a [u8], key: &[u8]) -> ScramblerLetters<'a> {
+        ScramblerLetters { scramblers: self, input: input.iter(), position: key_index(key) }
+    }
+
+    // Encrypts input for LANES start positions (as returned by key_index) in lock-step,
+    // each item holding the current letter for all the lanes. The lanes are independent
+    // so the lookups of the different lanes can overlap.
+    pub fn encrypt_lanes<'a>(&'a self, input: &'a [u8], positions: [usize; LANES]) -> LaneLetters<'a> {
+        LaneLetters { scramblers: self, input: input.iter(), positions }
+    }
+
+    // The scrambler at the position with the given index, without stepping first.
+    pub fn scrambler(&self, position: usize) -> &[u8; 26] {
+        &self.scramblers[position]
+    }
+}
+
+pub struct ScramblerLetters<'a> {
+    scramblers: &'a Scramblers,
+    input: slice::Iter<'a, u8>,
+    position: usize,
+}
+
+pub const LANES: usize = 16;
+
+pub struct LaneLetters<'a> {
+    scramblers: &'a Scramblers,
+    input: slice::Iter<'a, u8>,
+    positions: [usize; LANES],
+}
+
+impl<'a> Iterator for LaneLetters<'a> {
+    type Item = [u8; LANES];
+
+    fn next(&mut self) -> Option<[u8; LANES]> {
+        self.input.next().map(|&c| {
+            let mut letters = [0u8; LANES];
+            for (letter, position) in letters.iter_mut().zip(self.positions.iter_mut()) {
+                *position = self.scramblers.next[*position] as usize;
+                *letter = self.scramblers.scramblers[*position][c as usize];
+            }
+            letters
+        })
+    }
+}
+
+impl<'a> Iterator for ScramblerLetters<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        self.input.next().map(|&c| {
+            self.position = self.scramblers.next[self.position] as usize;
+            self.scramblers.scramblers[self.position][c as usize]
+        })
+    }
+}
+
+// Offsets of the rotors (position minus ring setting) after stepping for each letter of
+// a message of length len. Two settings leading to the same offsets encrypt all
+// messages of this length in the same way.
+fn offsets(tables: &Tables, key: &Key, len: usize) -> Vec<[u8; 3]> {
+    let rotors = rotor_array(&key.rotors);
+    let rings = &key.rings;
+    let mut state = moving_state(key);
+    (0..len).map(|_| {
+        step_with(&mut state, |idx, position| at_turnover(tables.turnovers[rotors[idx]], position));
+        [sub26(state[0], rings[0]), sub26(state[1], rings[1]), sub26(state[2], rings[2])]
+    }).collect()
+}
+
+// Returns a key equivalent to the given one for messages of length len, with as many
+// rings set to A as possible starting from the leftmost rotor. The left ring, and the
+// ring of the greek wheel, can always be set to A.
+pub fn canonical_key(tables: &Tables, key: &Key, len: usize) -> Key {
+    let reference = offsets(tables, key, len);
+    let mut key = key.clone();
+    if key.rotors.len() == 4 {
+        key.positions[0] = sub26(key.positions[0], key.rings[3]);
+        key.rings[3] = 0;
+    }
+    for ring_idx in (0..3).rev() {
+        let position_idx = key.positions.len() - 1 - ring_idx;
+        let mut new_key = key.clone();
+        new_key.positions[position_idx] = sub26(key.positions[position_idx], key.rings[ring_idx]);
+        new_key.rings[ring_idx] = 0;
+        if offsets(tables, &new_key, len) == reference {
+            key = new_key;
+        }
+    }
+    key
+}
+
+#[cfg(test)]
 fn test_one(plaintext: &str, ciphertext: &str, rotor_config: &Vec<u8>, key: &str, rings: &str) {
-    let computed_ciphertext = encrypt(plaintext, rotor_config, key, rings);
-    let computed_plaintext = encrypt(ciphertext, rotor_config, key, rings);
+    let computed_ciphertext = encrypt(plaintext, rotor_config, key, rings).unwrap();
+    let computed_plaintext = encrypt(ciphertext, rotor_config, key, rings).unwrap();
     assert_eq!(computed_plaintext, plaintext);
     assert_eq!(computed_ciphertext, ciphertext);
 }
@@ -182,13 +808,367 @@ fn test_one(plaintext: &str, ciphertext: &str, rotor_config: &Vec<u8>, key: &str
 #[test]
 fn encrypt_tests() {
     test_one(
-        "QUEJAIMEAFAIREAPPRENDREUNNOMBREUTILEAUXSAGESIMMORTELARCHIMEDEARTISTEINGENIEURQUIDETONJUGEMENTPEUTPRISERLAVALEURPOURMOITONPROBLEMEEUTDEPAREILSAVANTAGES".as_slice(),
-        "UBTSGAGKIOJYHNNGYGWDIEXLIQQHDVALZBFLTKVPIDHNHPETEHGGEEKDCCGBSWDQJGYFPUDHIVBWNLTJHPJPTMHJYFPKSYUBUOPOTFHSJJBFCVUJVJWSMDJVQCZKEMBYLBJFIZRDZFCIQORVGBOBIT".as_slice(),
-        &vec![ 0u8, 1, 2 ], "AAA".as_slice(), "AAA".as_slice()
+        "QUEJAIMEAFAIREAPPRENDREUNNOMBREUTILEAUXSAGESIMMORTELARCHIMEDEARTISTEINGENIEURQUIDETONJUGEMENTPEUTPRISERLAVALEURPOURMOITONPROBLEMEEUTDEPAREILSAVANTAGES",
+        "UBTSGAGKIOJYHNNGYGWDIEXLIQQHDVALZBFLTKVPIDHNHPETEHGGEEKDCCGBSWDQJGYFPUDHIVBWNLTJHPJPTMHJYFPKSYUBUOPOTFHSJJBFCVUJVJWSMDJVQCZKEMBYLBJFIZRDZFCIQORVGBOBIT",
+        &vec![ 0u8, 1, 2 ], "AAA", "AAA"
     );
     test_one(
-        "HELLOWORLD".as_slice(),
-        "CDMOGOSHXC".as_slice(),
-        &vec![ 0u8, 1, 2 ], "LMZ".as_slice(), "AAA".as_slice()
+        "HELLOWORLD",
+        "CDMOGOSHXC",
+        &vec![ 0u8, 1, 2 ], "LMZ", "AAA"
     );
+    // The lines are enciphered as one message, keeping their lengths.
+    let key = Key::new(vec![ 0u8, 1, 2 ], input_to_u8("LMZ"), vec![ 0u8, 0, 0 ]);
+    assert_eq!(encrypt_lines(&world(), &key, "HEL\nLO WORLD\n\n").unwrap(), vec!["CDM", "OGOSHXC", ""]);
+}
+
+// A key with its components given from left to right, as in the key sheets.
+#[cfg(test)]
+fn historical_key(reflector: &str, rotors: &str, rings: &str, positions: &str, plugboard: &str) -> Key {
+    Key {
+        rotors: rotors.split_whitespace().rev().map(|name| rotor_from_name(name).unwrap()).collect(),
+        reflector: reflector_from_name(reflector).unwrap(),
+        rings: input_to_u8(rings).into_iter().rev().collect(),
+        positions: input_to_u8(positions),
+        plugboard: parse_plugboard(plugboard).unwrap(),
+    }
+}
+
+// Messages whose decryption is published with their keys, checked in both directions.
+// Between them they cover reflectors A, B and C-thin, the naval rotors with two notches,
+// a greek wheel, ring settings, plugboards and the double stepping of the middle rotor.
+#[test]
+fn historical_tests() {
+    let world = world();
+    let vectors = [
+        // The example of the 1930 operating instructions of the Enigma I.
+        (historical_key("A", "II I III", "XMV", "ABL", "AM FI NV PS TU WZ"),
+         "GCDSEAHUGWTQGRKVLFGXUCALXVYMIGMMNMFDXTGNVHVRMMEVOUYFZSLRHDRRXFJWCFHUHMUNZEFRDISIKBGPMYVXUZ",
+         "FEINDLIQEINFANTERIEKOLONNEBEOBAQTETXANFANGSUEDAUSGANGBAERWALDEXENDEDREIKMOSTWAERTSNEUSTADT"),
+        // Operation Barbarossa, 7 July 1941, both parts of the message, the message keys
+        // BLA and LSD being sent enciphered at WXC and CRS.
+        (historical_key("B", "II IV V", "BUL", "BLA", "AV BS CG DL FU HZ IN KM OW RX"),
+         "EDPUDNRGYSZRCXNUYTPOMRMBOFKTBZREZKMLXLVEFGUEYSIOZVEQMIKUBPMMYLKLTTDEISMDICAGYKUACTCDOMOHWXMUUIAUBSTSLRNBZSZWNRFXWFYSSXJZVIJHIDISHPRKLKAYUPADTXQSPINQMATLPIFSVKDASCTACDPBOPVHJK",
+         "AUFKLXABTEILUNGXVONXKURTINOWAXKURTINOWAXNORDWESTLXSEBEZXSEBEZXUAFFLIEGERSTRASZERIQTUNGXDUBROWKIXDUBROWKIXOPOTSCHKAXOPOTSCHKAXUMXEINSAQTDREINULLXUHRANGETRETENXANGRIFFXINFXRGTX"),
+        (historical_key("B", "II IV V", "BUL", "WXC", "AV BS CG DL FU HZ IN KM OW RX"), "KCH", "BLA"),
+        (historical_key("B", "II IV V", "BUL", "LSD", "AV BS CG DL FU HZ IN KM OW RX"),
+         "SFBWDNJUSEGQOBHKRTAREEZMWKPPRBXOHDROEQGBBGTQVPGVKBVVGBIMHUSZYDAJQIROAXSSSNREHYGGRPISEZBOVMQIEMMZCYSGQDGRERVBILEKXYQIRGIRQNRDNVRXCYYTNJR",
+         "DREIGEHTLANGSAMABERSIQERVORWAERTSXEINSSIEBENNULLSEQSXUHRXROEMXEINSXINFRGTXDREIXAUFFLIEGERSTRASZEMITANFANGXEINSSEQSXKMXKMXOSTWXKAMENECXK"),
+        (historical_key("B", "II IV V", "BUL", "CRS", "AV BS CG DL FU HZ IN KM OW RX"), "YPJ", "LSD"),
+        // The Scharnhorst, 26 December 1943, on a naval M3.
+        (historical_key("B", "III VI VIII", "AHM", "UZV", "AN EZ HK IJ LR MQ OT PV SW UX"),
+         "YKAENZAPMSCHZBFOCUVMRMDPYCOFHADZIZMEFXTHFLOLPZLFGGBOTGOXGRETDWTJIQHLMXVJWKZUASTR",
+         "STEUEREJTANAFJORDJANSTANDORTQUAAACCCVIERNEUNNEUNZWOFAHRTZWONULSMXXSCHARNHORSTHCO"),
+        // Dönitz being appointed as Hitler's successor, 1 May 1945, on the M4.
+        (historical_key("C-thin", "Beta V VI VIII", "EPEL", "CDSZ", "AE BF CM DQ HU JN LX PR SZ VW"),
+         "LANOTCTOUARBBFPMHPHGCZXTDYGAHGUFXGEWKBLKGJWLQXXTGPJJAVTOCKZFSLPPQIHZFXOEBWIIEKFZLCLOAQJULJOYHSSMBBGWHZANVOIIPYRBRTDJQDJJOQKCXWDNBBTYVXLYTAPGVEATXSONPNYNQFUDBBHHVWEPYEYDOHNLXKZDNWRHDUWUJUMWWVIIWZXIVIUQDRHYMNCYEFUAPNHOTKHKGDNPSAKNUAGHJZSMJBMHVTREQEDGXHLZWIFUSKDQVELNMIMITHBHDBWVHDFYHJOQIHORTDJDBWXEMEAYXGYQXOHFDMYUXXNOJAZRSGHPLWMLRECWWUTLRTTVLBHYOORGLGOWUXNXHMHYFAACQEKTHSJW",
+         "KRKRALLEXXFOLGENDESISTSOFORTBEKANNTZUGEBENXXICHHABEFOLGELNBEBEFEHLERHALTENXXJANSTERLEDESBISHERIGXNREICHSMARSCHALLSJGOERINGJSETZTDERFUEHRERSIEYHVRRGRZSSADMIRALYALSSEINENNACHFOLGEREINXSCHRIFTLSCHEVOLLMACHTUNTERWEGSXABSOFORTSOLLENSIESAEMTLICHEMASSNAHMENVERFUEGENYDIESICHAUSDERGEGENWAERTIGENLAGEERGEBENXGEZXREICHSLEITEIKKTULPEKKJBORMANNJXXOBXDXMMMDURNHFKSTXKOMXADMXUUUBOOIEXKP"),
+    ];
+    for &(ref key, ciphertext, plaintext) in vectors.iter() {
+        key.check().unwrap();
+        let to_string = |letters: Vec<u8>| -> String { letters.iter().map(|&c| chr(c)).collect() };
+        assert_eq!(to_string(encrypt_key(&world, key, &input_to_u8(ciphertext)).unwrap()), plaintext, "{}", key);
+        assert_eq!(to_string(encrypt_key(&world, key, &input_to_u8(plaintext)).unwrap()), ciphertext, "{}", key);
+        let traces = trace_key(&world, key, &input_to_u8(ciphertext)).unwrap();
+        assert_eq!(to_string(traces.iter().map(|trace| trace.output).collect()), plaintext);
+    }
+    // The double step usually used to describe it: with rotors I-II-III, the middle rotor
+    // moves again as soon as it reaches its notch, taking the left rotor along.
+    let mut machine = Machine::new(historical_key("B", "I II III", "AAA", "ADU", "")).unwrap();
+    let windows: Vec<String> = (0..4).map(|_| { machine.press(0).unwrap(); machine.key().positions.iter().map(|&c| chr(c)).collect() }).collect();
+    assert_eq!(windows, vec!["ADV", "AEW", "BFX", "BFY"]);
+}
+
+#[test]
+fn encrypt_error_tests() {
+    let rotor_config = vec![ 0u8, 1, 2 ];
+    assert_eq!(encrypt("hello, world", &rotor_config, "lmz", "aaa"), Ok("CDMOGOSHXC".to_string()));
+    assert_eq!(encrypt("HELLO", &rotor_config, "LM", "AAA"), Err(Error::Length { what: "key", value: "LM".to_string(), expected: 3 }));
+    assert_eq!(encrypt("HELLO", &rotor_config, "LMZ", "A-A"), Err(Error::InvalidLetter { what: "rings", letter: '-' }));
+    assert_eq!(encrypt("HELLO", &vec![ 0u8, 1, 10 ], "LMZ", "AAA"), Err(Error::InvalidRotor(10)));
+    assert_eq!(encrypt("HELLO", &vec![ 0u8, 1, 0 ], "LMZ", "AAA"), Err(Error::DuplicateRotor("I".to_string())));
+    // The machine of encrypt has reflector B, so exactly three rotors out of I-VIII.
+    assert!(matches!(encrypt("HELLO", &vec![], "", ""), Err(Error::Key(_))));
+    assert!(matches!(encrypt("HELLO", &vec![ 0u8, 1, 2, 3, 4 ], "AAAAA", "AAAAA"), Err(Error::Key(_))));
+    assert!(matches!(encrypt("HELLO", &vec![ 0u8, 1, 2, 8 ], "AAAA", "AAAA"), Err(Error::Key(_))));
+    assert!(matches!(encrypt("HELLO", &vec![ 0u8, 8, 2 ], "AAA", "AAA"), Err(Error::Key(_))));
+    // The functions taking letters as indices check them and the key as well.
+    let world = world();
+    let tables = tables(&world);
+    assert_eq!(encrypt_u8(&world, &vec![ 1u8, 2 ], &vec![ 0u8, 1, 12 ], &vec![ 0u8, 0, 0 ], &vec![ 0u8, 0, 0 ]), Err(Error::InvalidRotor(12)));
+    assert_eq!(encrypt_u8(&world, &vec![ 1u8, 26 ], &rotor_config, &vec![ 0u8, 0, 0 ], &vec![ 0u8, 0, 0 ]), Err(Error::LetterIndex(26)));
+    assert!(matches!(encrypt_u8(&world, &vec![ 1u8, 2 ], &rotor_config, &vec![ 0u8, 0 ], &vec![ 0u8, 0, 0 ]), Err(Error::Key(_))));
+    let key = Key::new(rotor_config.clone(), vec![ 0u8, 0, 0 ], vec![ 0u8, 0, 0 ]);
+    let invalid = Key { rotors: vec![ 0u8, 1 ], .. key.clone() };
+    assert_eq!(encrypt_key(&world, &key, &[ 0u8, 30 ]), Err(Error::LetterIndex(30)));
+    assert!(matches!(encrypt_key(&world, &invalid, &[ 0u8 ]), Err(Error::Key(_))));
+    assert!(trace_key(&world, &key, &[ 26u8 ]).is_err() && trace_key(&world, &invalid, &[ 0u8 ]).is_err());
+    assert!(encrypt_iter(&tables, &key, &[ 26u8 ]).is_err() && encrypt_iter(&tables, &invalid, &[ 0u8 ]).is_err());
+    assert!(scramblers(&tables, &invalid).is_err());
+}
+
+#[test]
+fn tables_tests() {
+    let world = world();
+    let tables = tables(&world);
+    let input = input_to_u8("THEQUICKBROWNFOXJUMPSOVERTHELAZYDOGTHEQUICKBROWNFOXJUMPSOVERTHELAZYDOG");
+    for &(ref rotor_config, key, rings, reflector, plugboard) in [
+        (vec![0u8, 1, 2], "AAA", "AAA", 1, ""),
+        (vec![4u8, 2, 0], "QEV", "BZK", 0, "AV BS CG DL FU HZ IN KM OW RX"),
+        (vec![3u8, 0, 1], "ZDU", "XAM", 2, "QT"),
+    ].iter() {
+        let key = Key {
+            rotors: rotor_config.clone(),
+            reflector,
+            rings: input_to_u8(rings),
+            positions: input_to_u8(key),
+            plugboard: parse_plugboard(plugboard).unwrap(),
+        };
+        let expected = encrypt_key(&world, &key, &input).unwrap();
+        if plugboard.is_empty() && reflector == 1 {
+            assert_eq!(encrypt_u8(&world, &input, rotor_config, &key.positions, &key.rings).unwrap(), expected);
+        }
+        let computed: Vec<u8> = encrypt_iter(&tables, &key, &input).unwrap().collect();
+        assert_eq!(computed, expected);
+        let scramblers = scramblers(&tables, &key).unwrap();
+        let computed: Vec<u8> = scramblers.encrypt_iter(&input, &key.positions).collect();
+        assert_eq!(computed, expected);
+        let positions: [usize; LANES] = std::array::from_fn(|lane| (key_index(&key.positions) + 1000 * lane) % (26 * 26 * 26));
+        let lanes: Vec<[u8; LANES]> = scramblers.encrypt_lanes(&input, positions).collect();
+        for lane in 0..LANES {
+            let key = Key { positions: index_key(positions[lane]).to_vec(), .. key.clone() };
+            let expected = encrypt_key(&world, &key, &input).unwrap();
+            let computed: Vec<u8> = lanes.iter().map(|letters| letters[lane]).collect();
+            assert_eq!(computed, expected);
+        }
+    }
+}
+
+#[test]
+fn canonical_key_tests() {
+    let world = world();
+    let tables = tables(&world);
+    let input = input_to_u8("THEQUICKBROWNFOXJUMPSOVERTHELAZYDOG");
+    for &(positions, rings, expected_positions, expected_rings, len) in [
+        // The middle rotor does not reach its turnover, only the right ring matters.
+        ("KDX", "CFR", "TYX", "CAA", 35),
+        // The left rotor steps during the message so the middle ring has an effect.
+        ("KPX", "CFR", "TPX", "CFA", 35),
+        // Short message, the middle rotor does not step either.
+        ("KDA", "CFR", "TYY", "AAA", 10),
+    ].iter() {
+        let mut key = Key::new(vec![ 2u8, 0, 4 ], input_to_u8(positions), input_to_u8(rings));
+        key.plugboard = parse_plugboard("AZ BY").unwrap();
+        let canonical = canonical_key(&tables, &key, len);
+        assert_eq!(canonical.positions, input_to_u8(expected_positions));
+        assert_eq!(canonical.rings, input_to_u8(expected_rings));
+        let input = input[..len].to_vec();
+        assert_eq!(encrypt_key(&world, &canonical, &input).unwrap(), encrypt_key(&world, &key, &input).unwrap());
+    }
+}
+
+#[test]
+fn key_tests() {
+    assert_eq!(plugboard_pairs(&parse_plugboard("av BS cg").unwrap()), "AV BS CG");
+    assert!(parse_plugboard("AB BC").is_err());
+    assert!(parse_plugboard("AA").is_err());
+    assert!(parse_plugboard("ABC").is_err());
+    assert!(parse_plugboard("A1").is_err());
+    assert_eq!(rotor_from_name("iv"), Some(3));
+    assert_eq!(reflector_from_name("B-Thin"), Some(3));
+    let mut key = Key::new(vec![ 0u8, 1, 2 ], input_to_u8("TDX"), input_to_u8("CFA"));
+    key.plugboard = parse_plugboard("AB").unwrap();
+    assert_eq!(format!("{}", key), "reflector B rotors III-II-I rings AFC positions TDX plugboard AB");
+    assert_eq!(key.check(), Ok(()));
+    assert!(Key { reflector: 3, .. key.clone() }.check().is_err());
+    assert!(Key { rotors: vec![ 0u8, 1, 8 ], .. key.clone() }.check().is_err());
+    assert!(Key { rotors: vec![ 0u8, 1, 2, 3 ], rings: vec![0; 4], positions: vec![0; 4], reflector: 3, .. key.clone() }.check().is_err());
+    assert!(Key { rotors: vec![ 0u8, 1, 2, 8 ], rings: vec![0; 4], positions: vec![0; 4], reflector: 3, .. key.clone() }.check().is_ok());
+    assert!(Key { positions: vec![ 0u8, 1 ], .. key.clone() }.check().is_err());
+}
+
+#[test]
+fn m4_tests() {
+    let world = world();
+    let tables = tables(&world);
+    let input = input_to_u8("THEQUICKBROWNFOXJUMPSOVERTHELAZYDOGTHEQUICKBROWNFOXJUMPSOVERTHELAZYDOG");
+    // At position A with its ring at A, Beta with B-thin is the same as reflector B and
+    // Gamma with C-thin the same as reflector C.
+    for &(greek, thin, thick) in [(GREEK_ROTORS[0], 3u8, 1u8), (GREEK_ROTORS[1], 4, 2)].iter() {
+        let mut key = Key::new(vec![ 5u8, 0, 7 ], input_to_u8("QMX"), input_to_u8("BZK"));
+        key.reflector = thick;
+        key.plugboard = parse_plugboard("AV BS CG DL").unwrap();
+        let m4 = Key {
+            rotors: vec![ 5u8, 0, 7, greek ], reflector: thin, rings: input_to_u8("BZKA"),
+            positions: input_to_u8("AQMX"), plugboard: key.plugboard.clone(),
+        };
+        let expected = encrypt_key(&world, &key, &input).unwrap();
+        assert_eq!(encrypt_key(&world, &m4, &input).unwrap(), expected);
+        let computed: Vec<u8> = encrypt_iter(&tables, &m4, &input).unwrap().collect();
+        assert_eq!(computed, expected);
+        let computed: Vec<u8> = scramblers(&tables, &m4).unwrap().encrypt_iter(&input, &m4.positions[1..]).collect();
+        assert_eq!(computed, expected);
+        // Other greek settings give another reflector, only position minus ring matters.
+        let moved = Key { positions: input_to_u8("FQMX"), rings: input_to_u8("BZKC"), .. m4.clone() };
+        let expected = encrypt_key(&world, &moved, &input).unwrap();
+        assert!(expected != encrypt_key(&world, &m4, &input).unwrap());
+        let computed: Vec<u8> = encrypt_iter(&tables, &moved, &input).unwrap().collect();
+        assert_eq!(computed, expected);
+        let canonical = canonical_key(&tables, &moved, input.len());
+        assert_eq!((canonical.positions[0], canonical.rings[3]), (3, 0));
+        assert_eq!(encrypt_key(&world, &canonical, &input).unwrap(), expected);
+    }
+    // The naval rotors have two notches.
+    let key = Key::new(vec![ 5u8, 0, 1 ], input_to_u8("AAM"), input_to_u8("AAA"));
+    assert_eq!(offsets(&tables, &key, 1)[0], [13, 1, 0]);
+    let key = Key { positions: input_to_u8("AAZ"), .. key };
+    assert_eq!(offsets(&tables, &key, 1)[0], [0, 1, 0]);
+    assert_eq!(rotor_from_name("gamma"), Some(GREEK_ROTORS[1]));
+}
+
+#[test]
+fn machine_tests() {
+    let mut key = Key::new(vec![4, 1, 2], vec![16, 4, 21], vec![1, 20, 11]);
+    key.plugboard = parse_plugboard("AV BS CG DL").unwrap();
+    let plaintext = input_to_u8("THEMIDDLEROTORSTEPSTWICEINAROWHERE");
+    let ciphertext = encrypt_key(&world(), &key, &plaintext).unwrap();
+    let mut machine = Machine::new(key.clone()).unwrap();
+    let lamps: Vec<u8> = plaintext.iter().map(|&c| machine.press(c).unwrap()).collect();
+    assert_eq!(lamps, ciphertext);
+    // Undoing every key press brings the rotors back to the start.
+    let end = machine.key();
+    assert_ne!(end.positions, key.positions);
+    while machine.backspace() {}
+    assert_eq!(machine.key(), key);
+    // A mistyped letter is undone and typed again.
+    assert_eq!(machine.press(0).unwrap(), encrypt_key(&world(), &key, &[0]).unwrap()[0]);
+    machine.backspace();
+    let lamps: Vec<u8> = ciphertext.iter().map(|&c| machine.press(c).unwrap()).collect();
+    assert_eq!(lamps, plaintext);
+    assert_eq!(machine.key(), end);
+    // A letter index without a key is rejected without moving the rotors.
+    assert!(machine.press(26).is_err());
+    assert_eq!(machine.key(), end);
+    let mut thin = Key { reflector: 1, .. key.clone() };
+    thin.rotors.push(8);
+    assert!(machine.set_key(thin).is_err());
+    assert_eq!(machine.key(), end);
+}
+
+#[test]
+fn trace_tests() {
+    let world = world();
+    // The path usually given for the first A typed on rotors I-II-III at AAA, e.g. in the
+    // description of the Enigma I on Wikipedia.
+    let key = Key::new(vec![2, 1, 0], vec![0, 0, 0], vec![0, 0, 0]);
+    let trace = &trace_key(&world, &key, &[0]).unwrap()[0];
+    let letters = |letters: &Vec<u8>| -> String { letters.iter().map(|&c| chr(c)).collect() };
+    assert_eq!(letters(&trace.positions), "AAB");
+    assert_eq!((chr(trace.plugboard), letters(&trace.forward), chr(trace.reflector), letters(&trace.backward), chr(trace.output)),
+               ('A', "CDF".to_string(), 'S', "SEB".to_string(), 'B'));
+    // The traces agree with encrypt_key, including the plugboard and a greek wheel.
+    let mut key = Key::new(vec![7, 1, 4, 8], vec![3, 24, 12, 25], vec![0, 5, 11, 19]);
+    key.reflector = 3;
+    key.plugboard = parse_plugboard("AT BL DF GJ").unwrap();
+    let input = input_to_u8("VERIFYINGTHEWIRINGS");
+    let traces = trace_key(&world, &key, &input).unwrap();
+    assert_eq!(traces.iter().map(|trace| trace.output).collect::<Vec<u8>>(), encrypt_key(&world, &key, &input).unwrap());
+    for (trace, &c) in traces.iter().zip(input.iter()) {
+        assert_eq!((trace.input, trace.plugboard, trace.forward.len(), trace.backward.len()), (c, key.plugboard[c as usize], 4, 4));
+        assert_eq!(trace.output, key.plugboard[trace.backward[3] as usize]);
+    }
+    assert_eq!(traces.last().unwrap().positions[0], 3);
+}
+
+// A random valid key for the property tests: three rotors out of I to VIII with reflector
+// A, B or C, or four with a greek wheel and a thin reflector, and up to 13 stecker pairs.
+// With single_notch, the moving rotors are taken from I to V.
+#[cfg(test)]
+fn random_key(rng: &mut crate::cryptanalysis::Rng, single_notch: bool) -> Key {
+    let mut rotors: Vec<u8> = (0..if single_notch { ARMY_ROTORS } else { NAVAL_ROTORS }).collect();
+    rng.shuffle(&mut rotors);
+    rotors.truncate(3);
+    let m4 = rng.below(2) == 0;
+    if m4 {
+        rotors.push(GREEK_ROTORS[rng.below(2)]);
+    }
+    let letters = |rng: &mut crate::cryptanalysis::Rng| (0..rotors.len()).map(|_| rng.below(26) as u8).collect::<Vec<u8>>();
+    let mut key = Key::new(rotors.clone(), letters(rng), letters(rng));
+    key.reflector = if m4 { THIN_REFLECTORS[rng.below(2)] } else { rng.below(3) as u8 };
+    let mut steckered: Vec<u8> = (0..26).collect();
+    rng.shuffle(&mut steckered);
+    for pair in steckered.chunks(2).take(rng.below(14)) {
+        key.plugboard[pair[0] as usize] = pair[1];
+        key.plugboard[pair[1] as usize] = pair[0];
+    }
+    key.check().unwrap();
+    key
+}
+
+#[test]
+fn property_tests() {
+    let world = world();
+    let tables = tables(&world);
+    let mut rng = crate::cryptanalysis::Rng::new(50);
+    for _ in 0..200 {
+        let key = random_key(&mut rng, false);
+        let plaintext: Vec<u8> = (0..rng.below(300)).map(|_| rng.below(26) as u8).collect();
+        let ciphertext = encrypt_key(&world, &key, &plaintext).unwrap();
+        // The machine is its own inverse and never enciphers a letter as itself.
+        assert_eq!(encrypt_key(&world, &key, &ciphertext).unwrap(), plaintext, "{}", key);
+        assert!(plaintext.iter().zip(ciphertext.iter()).all(|(p, c)| p != c), "{}", key);
+        assert_eq!(encrypt_iter(&tables, &key, &plaintext).unwrap().collect::<Vec<u8>>(), ciphertext, "{}", key);
+        // At each position the scrambler is a product of 13 swaps, i.e. an involution
+        // without fixed points.
+        let mut machine = Machine::new(key.clone()).unwrap();
+        for _ in 0..30 {
+            let scrambler: Vec<u8> = (0..26).map(|c| { let lamp = machine.press(c).unwrap(); machine.backspace(); lamp }).collect();
+            assert!((0..26).all(|c| scrambler[c] != c as u8 && scrambler[scrambler[c] as usize] == c as u8), "{}", key);
+            machine.press(rng.below(26) as u8).unwrap();
+        }
+        if key.rotors.len() == 3 {
+            let scramblers = scramblers(&tables, &key).unwrap();
+            let scrambler = scramblers.scrambler(key_index(&key.positions));
+            assert!((0..26).all(|c| scrambler[c] != c as u8 && scrambler[scrambler[c] as usize] == c as u8), "{}", key);
+        }
+    }
+    // encrypt agrees with encrypt_u8, lowercase letters being enciphered as uppercase
+    // ones and other characters being dropped.
+    for _ in 0..100 {
+        let mut rotors: Vec<u8> = (0..NAVAL_ROTORS).collect();
+        rng.shuffle(&mut rotors);
+        rotors.truncate(3);
+        let positions: Vec<u8> = (0..3).map(|_| rng.below(26) as u8).collect();
+        let rings: Vec<u8> = (0..3).map(|_| rng.below(26) as u8).collect();
+        let input: String = (0..rng.below(100)).map(|_| b"ABCXYZabcxyz 0.,"[rng.below(16)] as char).collect();
+        let expected: String = encrypt_u8(&world, &input_to_u8(&input), &rotors, &positions, &rings).unwrap().iter().map(|&c| chr(c)).collect();
+        let letters = |letters: &Vec<u8>| -> String { letters.iter().map(|&c| chr(c)).collect() };
+        assert_eq!(encrypt(&input, &rotors, &letters(&positions), &letters(&rings).to_lowercase()).unwrap(), expected);
+    }
+}
+
+// With lever stepping and one notch per rotor, the right rotor makes the middle one step
+// every 26 letters, and the middle one goes round in 25 of these steps as its double step
+// saves one. The left rotor moving once per turn of the middle one, the positions come
+// back after 26 * 25 * 26 = 16900 letters. The first few
+// positions may not be on the cycle because of the double step so the period is measured
+// once the machine has been running for a while.
+#[test]
+fn period_tests() {
+    let mut rng = crate::cryptanalysis::Rng::new(16900);
+    for _ in 0..4 {
+        let key = random_key(&mut rng, true);
+        let mut machine = Machine::new(key.clone()).unwrap();
+        for _ in 0..26 * 26 {
+            machine.press(0).unwrap();
+        }
+        let start = machine.key().positions;
+        let mut period = 0;
+        loop {
+            machine.press(0).unwrap();
+            period += 1;
+            if machine.key().positions == start { break; }
+        }
+        assert_eq!(period, 16900, "{}", key);
+    }
 }
@@ -0,0 +1,69 @@
+use std::error;
+use std::fmt;
+
+// The errors reported by the library and the command line, see exit_code for how they
+// are reported to the shell.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    // A key, ring setting or other list of letters with the wrong number of letters.
+    Length { what: &'static str, value: String, expected: usize },
+    InvalidLetter { what: &'static str, letter: char },
+    // A rotor index which does not correspond to any rotor.
+    InvalidRotor(u8),
+    // A letter given as an index which is not below 26, e.g. in the input of encrypt_key.
+    LetterIndex(u8),
+    UnknownRotor(String),
+    UnknownReflector(String),
+    DuplicateRotor(String),
+    // A key that cannot be set on a machine, e.g. a thin reflector with three rotors.
+    Key(String),
+    // A malformed plugboard, e.g. with a letter used twice.
+    Plugboard(String),
+    NGrams(String),
+    Checkpoint(String),
+    Transmission(String),
+    // A search that cannot be done, e.g. resuming a search in depth.
+    Search(String),
+    // Malformed JSON input, or JSON missing the fields of a message.
+    Json(String),
+    // Invalid command line arguments.
+    Usage(String),
+    Io { path: String, message: String },
+}
+
+impl Error {
+    // 2 for a command line that could not be understood, 3 when a file or the standard
+    // input could not be read or written and 1 for invalid keys, messages or searches.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Usage(_) => 2,
+            Error::Io { .. } => 3,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Length { what, ref value, expected } => write!(f, "{} '{}' should consist of {} letters", what, value, expected),
+            Error::InvalidLetter { what, letter } => write!(f, "invalid letter '{}' in {}", letter, what),
+            Error::InvalidRotor(rotor) => write!(f, "there is no rotor with index {}", rotor),
+            Error::LetterIndex(letter) => write!(f, "there is no letter with index {}", letter),
+            Error::UnknownRotor(ref name) => write!(f, "unknown rotor '{}'", name),
+            Error::UnknownReflector(ref name) => write!(f, "unknown reflector '{}'", name),
+            Error::DuplicateRotor(ref name) => write!(f, "rotor {} is used more than once", name),
+            Error::Key(ref message) => write!(f, "invalid key: {}", message),
+            Error::Plugboard(ref message) => write!(f, "invalid plugboard: {}", message),
+            Error::NGrams(ref message) => write!(f, "invalid n-grams: {}", message),
+            Error::Checkpoint(ref message) => write!(f, "invalid checkpoint: {}", message),
+            Error::Transmission(ref message) => write!(f, "invalid transmission: {}", message),
+            Error::Search(ref message) => write!(f, "cannot break: {}", message),
+            Error::Json(ref message) => write!(f, "invalid JSON input: {}", message),
+            Error::Usage(ref message) => write!(f, "{}", message),
+            Error::Io { ref path, ref message } => write!(f, "cannot access '{}': {}", path, message),
+        }
+    }
+}
+
+impl error::Error for Error {}
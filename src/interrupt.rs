@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set when the process receives SIGINT, so that long running commands can stop cleanly
+// and report what they have so far. It is the stop flag of the searches, which also set
+// it when their budget has elapsed.
+pub static INTERRUPTED : AtomicBool = AtomicBool::new(false);
+
+// Only set when the process receives SIGINT, to tell Ctrl-C from the end of a budget.
+pub static SIGNALLED : AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod imp {
+    use std::sync::atomic::Ordering;
+    use super::{INTERRUPTED, SIGNALLED};
+
+    const SIGINT : i32 = 2;
+    const SIG_DFL : usize = 0;
+
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    // Only async-signal-safe operations here. The default handler is restored so that a
+    // second Ctrl-C terminates the process if it does not stop quickly enough.
+    extern "C" fn on_sigint(_signum: i32) {
+        SIGNALLED.store(true, Ordering::SeqCst);
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        unsafe { signal(SIGINT, SIG_DFL); }
+    }
+
+    pub fn install() {
+        unsafe { signal(SIGINT, on_sigint as extern "C" fn(i32) as usize); }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub fn install() {}
+}
+
+// Catches the next Ctrl-C, on platforms other than unix this does nothing.
+pub fn install() {
+    SIGNALLED.store(false, Ordering::SeqCst);
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    imp::install();
+}
+
+// Clears the stop flag set by a search whose budget has elapsed, so that the next search
+// can run, unless Ctrl-C was pressed.
+pub fn clear_budget_stop() {
+    if !SIGNALLED.load(Ordering::SeqCst) {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,282 @@
+use std::fmt;
+use enigma::error::Error;
+
+// Just enough JSON for the output of the commands and the messages of batch jobs. Objects
+// keep their fields in order so that the output is stable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+    }
+
+    pub fn string(value: &str) -> Json { Json::String(value.to_string()) }
+
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|field| field.0 == name).map(|field| &field.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+// Compact, on a single line. Numbers that JSON cannot represent are written as null.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) if value.is_finite() => write!(f, "{}", value),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(ref value) => write_string(f, value),
+            Json::Array(ref values) => {
+                write!(f, "[")?;
+                for (idx, value) in values.iter().enumerate() {
+                    if 0 < idx { write!(f, ",")?; }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (idx, (name, value)) in fields.iter().enumerate() {
+                    if 0 < idx { write!(f, ",")?; }
+                    write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+// The deepest nesting of arrays and objects accepted, so that the recursive parser cannot
+// overflow the stack on inputs like [[[[...
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    input: &'a str,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&mut self, expected: &str) -> Error {
+        match self.chars.peek() {
+            Some(&(idx, c)) => Error::Json(format!("expected {} at offset {}, found '{}'", expected, idx, c)),
+            None => Error::Json(format!("expected {} at the end of the input", expected)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) { self.chars.next(); }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(&(_, c)) if c == expected => { self.chars.next(); Ok(()) },
+            _ => Err(self.error(&format!("'{}'", expected))),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, Error> {
+        for expected in word.chars() {
+            match self.chars.peek() {
+                Some(&(_, c)) if c == expected => { self.chars.next(); },
+                _ => return Err(self.error(word)),
+            }
+        }
+        Ok(value)
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match self.chars.peek().and_then(|&(_, c)| c.to_digit(16)) {
+                Some(digit) => { code = code * 16 + digit; self.chars.next(); },
+                None => return Err(self.error("a hexadecimal digit")),
+            }
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err(self.error("'\"'")),
+                Some((_, '"')) => return Ok(value),
+                Some((_, '\\')) => match self.chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('f') => value.push('\u{c}'),
+                    Some('u') => {
+                        let mut code = self.hex4()?;
+                        // A surrogate pair encodes the characters outside the BMP.
+                        if (0xD800..0xDC00).contains(&code) {
+                            self.literal("\\u", Json::Null)?;
+                            let low = self.hex4()?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return Err(Error::Json(format!("invalid low surrogate {:x} after {:x}", low, code)));
+                            }
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        }
+                        match char::from_u32(code) {
+                            Some(c) => value.push(c),
+                            None => return Err(Error::Json(format!("invalid character code {:x}", code))),
+                        }
+                    },
+                    Some(c @ ('"' | '\\' | '/')) => value.push(c),
+                    _ => return Err(self.error("an escape sequence")),
+                },
+                Some((_, c)) => value.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, Error> {
+        let start = self.chars.peek().map(|&(idx, _)| idx).unwrap_or(self.input.len());
+        let mut end = start;
+        while let Some(&(idx, c)) = self.chars.peek() {
+            if !(c.is_ascii_digit() || "+-.eE".contains(c)) { break; }
+            end = idx + 1;
+            self.chars.next();
+        }
+        match self.input[start..end].parse() {
+            Ok(value) => Ok(Json::Number(value)),
+            Err(_) => Err(Error::Json(format!("invalid number '{}' at offset {}", &self.input[start..end], start))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, Error> {
+        if MAX_DEPTH <= self.depth {
+            return Err(Error::Json(format!("values nested more than {} deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let value = self.nested_value();
+        self.depth -= 1;
+        value
+    }
+
+    fn nested_value(&mut self) -> Result<Json, Error> {
+        self.skip_whitespace();
+        match self.chars.peek().map(|&(_, c)| c) {
+            Some('n') => self.literal("null", Json::Null),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => {
+                self.chars.next();
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.chars.peek().is_some_and(|&(_, c)| c == ']') {
+                    self.chars.next();
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => (),
+                        Some((_, ']')) => return Ok(Json::Array(values)),
+                        _ => return Err(Error::Json("expected ',' or ']' in an array".to_string())),
+                    }
+                }
+            },
+            Some('{') => {
+                self.chars.next();
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.chars.peek().is_some_and(|&(_, c)| c == '}') {
+                    self.chars.next();
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => (),
+                        Some((_, '}')) => return Ok(Json::Object(fields)),
+                        _ => return Err(Error::Json("expected ',' or '}' in an object".to_string())),
+                    }
+                }
+            },
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("a value")),
+        }
+    }
+}
+
+// Parses a sequence of values separated by whitespace, so that both a single document
+// and JSON Lines, one value per line, are accepted.
+pub fn parse_all(input: &str) -> Result<Vec<Json>, Error> {
+    let mut parser = Parser { chars: input.char_indices().peekable(), input, depth: 0 };
+    let mut values = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        if parser.chars.peek().is_none() { return Ok(values); }
+        values.push(parser.value()?);
+    }
+}
+
+#[test]
+fn json_tests() {
+    let input = r#"{"id": 3, "text": "A\"B\\C\né😀", "key": {"rotors": ["I", "II"], "pairs": []}, "ok": true, "x": null, "f": -1.5e2}"#;
+    let values = parse_all(input).unwrap();
+    assert_eq!(values.len(), 1);
+    let value = &values[0];
+    assert_eq!(value.get("id"), Some(&Json::Number(3.)));
+    assert_eq!(value.get("text").and_then(|t| t.as_str()), Some("A\"B\\C\n\u{e9}\u{1F600}"));
+    assert_eq!(value.get("key").and_then(|k| k.get("rotors")), Some(&Json::Array(vec![Json::string("I"), Json::string("II")])));
+    assert_eq!(value.get("f"), Some(&Json::Number(-150.)));
+    assert_eq!(value.get("missing"), None);
+    // The output parses back to the same value.
+    assert_eq!(parse_all(&value.to_string()).unwrap(), values);
+    assert_eq!(Json::object(vec![("a", Json::Number(f64::NAN)), ("b", Json::string("\u{1}"))]).to_string(), r#"{"a":null,"b":"\u0001"}"#);
+    assert_eq!(parse_all("{\"a\": 1}\n{\"a\": 2}\n").unwrap().len(), 2);
+    assert_eq!(parse_all("[]").unwrap(), vec![Json::Array(Vec::new())]);
+    let nested = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+    assert!(parse_all(&nested).is_ok());
+    let too_deep = "[".repeat(100000);
+    for invalid in ["{\"a\" 1}", "[1, 2", "\"abc", "tru", "{\"a\": -}", "[1,]", "\"\\uD800\\u0041\"", "\"\\uD800", "\"\\uDC00\"", &too_deep].iter() {
+        assert!(matches!(parse_all(invalid), Err(Error::Json(_))), "{}", invalid);
+    }
+}
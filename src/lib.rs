@@ -0,0 +1,11 @@
+// The Enigma machine, the n-gram scorers and the attacks on top of them. The command
+// line in main.rs only parses arguments and prints the results of these modules.
+//
+// The oldest functions of encrypt.rs and cryptanalysis.rs keep their original style and
+// allow the clippy lints it triggers, the rest of the code is expected to pass clippy.
+pub mod encrypt;
+pub mod error;
+pub mod ngram;
+pub mod cryptanalysis;
+
+pub use error::Error;
@@ -1,61 +1,1422 @@
-#![allow(unstable)]
+use std::cmp;
+use std::fmt;
 use std::io;
-use std::os;
-mod encrypt;
-mod quadgram_data;
-mod cryptanalysis;
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::fs;
+use std::fs::File;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::env;
+use std::process;
+use enigma::{cryptanalysis, encrypt, ngram};
+use enigma::error::Error;
+mod interrupt;
+mod json;
+use json::Json;
 
-fn main_encrypt(args: &Vec<String>) {
-    if args.len() != 4 {
-        println!("Usage: {} encrypt KEY RNG", args[0]);
-    }
-    else {
-        let rotor_config = vec![ 0u8, 1, 2 ];
-        let key = args[2].as_slice(); 
-        let rings = args[3].as_slice();
-        if key.len() != 3 {
-            println!("Key '{}' has a length different from 3", key);
-        }
-        else if rings.len() != 3 {
-            println!("Rings '{}' has a length different from 3", key);
+// The arguments of a command: the options taking a value in the order given, the flags
+// and the positional arguments. Options can be given as --name VALUE or --name=VALUE.
+struct Args<'a> {
+    options: Vec<(&'a str, &'a str)>,
+    flags: Vec<&'a str>,
+    positional: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    fn flag(&self, name: &str) -> bool { self.flags.contains(&name) }
+}
+
+// flags are the options of the command that do not take a value, -h and --help being
+// accepted by all the commands.
+fn parse_args<'a>(args: &'a [String], flags: &[&str]) -> Result<Args<'a>, Error> {
+    let mut res = Args { options: Vec::new(), flags: Vec::new(), positional: Vec::new() };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            res.flags.push("--help");
         }
-        else {
-            match io::stdin().read_line() {
-                Ok(input) => {
-                    let output = encrypt::encrypt(input.as_slice(), &rotor_config, key, rings);
-                    println!("{}", output);
+        else if arg.starts_with("--") {
+            match arg.find('=') {
+                Some(idx) => res.options.push((&arg[..idx], &arg[idx + 1..])),
+                None if flags.contains(&arg.as_str()) => res.flags.push(arg),
+                None => match args.next() {
+                    Some(value) => res.options.push((arg, value)),
+                    None => return Err(Error::Usage(format!("missing value for {}", arg))),
                 },
-                Err(_) => ()
             }
         }
+        else {
+            res.positional.push(arg);
+        }
+    }
+    Ok(res)
+}
+
+// Reads the whole input, from the standard input unless a file is given, "-" also
+// standing for the standard input.
+fn read_input(path: Option<&str>) -> Result<String, Error> {
+    let mut input = String::new();
+    let (name, res) = match path {
+        None | Some("-") => ("stdin", io::stdin().read_to_string(&mut input)),
+        Some(path) => (path, File::open(path).and_then(|mut file| file.read_to_string(&mut input))),
+    };
+    match res {
+        Ok(_) => Ok(input),
+        Err(err) => Err(Error::Io { path: name.to_string(), message: err.to_string() }),
+    }
+}
+
+// Where the results of a command go, the standard output unless a file is given.
+struct Output {
+    path: String,
+    writer: Box<dyn Write>,
+}
+
+impl Output {
+    fn open(path: Option<&str>) -> Result<Output, Error> {
+        match path {
+            None | Some("-") => Ok(Output { path: "stdout".to_string(), writer: Box::new(io::stdout()) }),
+            Some(path) => match File::create(path) {
+                Ok(file) => Ok(Output { path: path.to_string(), writer: Box::new(BufWriter::new(file)) }),
+                Err(err) => Err(Error::Io { path: path.to_string(), message: err.to_string() }),
+            },
+        }
+    }
+
+    fn line(&mut self, line: fmt::Arguments) -> Result<(), Error> {
+        let res = writeln!(self.writer, "{}", line);
+        res.map_err(|err| Error::Io { path: self.path.clone(), message: err.to_string() })
+    }
+
+    fn close(mut self) -> Result<(), Error> {
+        let res = self.writer.flush();
+        res.map_err(|err| Error::Io { path: self.path.clone(), message: err.to_string() })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    // For break, the positions and score of the key found and the decryption, as printed
+    // by the original command and read by scripts.
+    Text,
+    // For break, the complete keys and the confidence in the decryption. The other
+    // commands print the same as for Text.
+    Verbose,
+    // One JSON object per line, or per message for batch jobs.
+    Json,
+}
+
+fn parse_format(value: &str) -> Result<Format, Error> {
+    match value.to_lowercase().as_str() {
+        "text" => Ok(Format::Text),
+        "verbose" => Ok(Format::Verbose),
+        "json" => Ok(Format::Json),
+        _ => Err(Error::Usage(format!("unknown format '{}', use text, verbose or json", value))),
     }
 }
 
-fn main_break(_args: &Vec<String>) {
-    match io::stdin().read_line() {
-        Ok(input) => {
-            match cryptanalysis::brute_force(input.as_slice()) {
-                None => println!("No optimal key found."),
-                Some((score, key, rotor_config, rings)) => {
-                    println!("{} {}", key, score);
-                    println!("{}", encrypt::encrypt(input.as_slice(), &rotor_config, key.as_slice(), rings.as_slice()));
+fn parse_input_format(value: &str) -> Result<Format, Error> {
+    match parse_format(value)? {
+        Format::Verbose => Err(Error::Usage("the input format should be text or json".to_string())),
+        format => Ok(format),
+    }
+}
+
+// The components of the key from left to right, as they are displayed.
+fn key_json(key: &encrypt::Key) -> Json {
+    Json::object(vec![
+        ("reflector", Json::string(encrypt::reflector_name(key.reflector))),
+        ("rotors", Json::Array(key.rotors.iter().rev().map(|&r| Json::string(encrypt::rotor_name(r))).collect())),
+        ("rings", Json::String(key.rings.iter().rev().map(|&r| encrypt::chr(r)).collect())),
+        ("positions", Json::String(letters(&key.positions))),
+        ("plugboard", Json::String(encrypt::plugboard_pairs(&key.plugboard))),
+    ])
+}
+
+fn confidence_json(confidence: &cryptanalysis::Confidence) -> Json {
+    Json::object(vec![
+        ("z_score", Json::Number(confidence.z_score)),
+        ("ic", Json::Number(confidence.ic)),
+        ("broken", Json::Bool(confidence.broken)),
+    ])
+}
+
+// The messages of a batch job, given as an array of objects or as one object per line.
+fn read_messages(input: &str) -> Result<Vec<Json>, Error> {
+    let mut messages = json::parse_all(input)?;
+    if let [Json::Array(_)] = messages.as_slice() {
+        if let Some(Json::Array(array)) = messages.pop() { messages = array; }
+    }
+    match messages.iter().position(|message| !matches!(*message, Json::Object(_))) {
+        Some(idx) => Err(Error::Json(format!("message {} is not an object", idx + 1))),
+        None => Ok(messages),
+    }
+}
+
+// The "id" of the message, its 1-based index when it has none.
+fn message_id(message: &Json, idx: usize) -> Json {
+    message.get("id").cloned().unwrap_or(Json::Number((idx + 1) as f64))
+}
+
+fn message_text<'a>(message: &'a Json, name: &str) -> Result<&'a str, Error> {
+    message.get(name).and_then(|text| text.as_str()).ok_or_else(|| Error::Json(format!("missing string \"{}\"", name)))
+}
+
+// The fields of an object of the message, e.g. the key, none if it is absent.
+fn message_fields<'a>(message: &'a Json, name: &str) -> Result<Vec<(&'a str, &'a Json)>, Error> {
+    match message.get(name) {
+        None => Ok(Vec::new()),
+        Some(Json::Object(fields)) => Ok(fields.iter().map(|(name, value)| (name.as_str(), value)).collect()),
+        Some(_) => Err(Error::Json(format!("\"{}\" should be an object", name))),
+    }
+}
+
+// The value of an option given in JSON as on the command line, a list of names being
+// given as an array or a string.
+fn option_value(name: &str, value: &Json) -> Result<String, Error> {
+    match *value {
+        Json::String(ref value) => Ok(value.clone()),
+        Json::Number(value) => Ok(value.to_string()),
+        Json::Array(ref values) => values.iter().map(|value| value.as_str().map(|v| v.to_string()))
+            .collect::<Option<Vec<String>>>().map(|values| values.join(" "))
+            .ok_or_else(|| Error::Json(format!("\"{}\" should be an array of strings", name))),
+        _ => Err(Error::Json(format!("invalid value for \"{}\"", name))),
+    }
+}
+
+// The messages of a batch job that cannot be processed are reported in the output, the
+// others being processed anyway.
+fn report_message_error(out: &mut Output, format: Format, id: Json, err: &Error) -> Result<(), Error> {
+    match format {
+        Format::Json => out.line(format_args!("{}", Json::object(vec![("id", id), ("error", Json::String(err.to_string()))]))),
+        Format::Text | Format::Verbose => {
+            let _ = writeln!(io::stderr(), "Error: message {}: {}", id, err);
+            Ok(())
+        },
+    }
+}
+
+static COMMANDS : &str = "\
+Commands:
+  encrypt, decrypt  encipher the input, both being the same on the Enigma
+  interactive       operate a simulated machine key by key
+  break             find the key of a message from its ciphertext only
+  merge             combine the results of break --shard
+  depth             break several messages sharing the same key
+  indicators        recover the day key and message keys of complete transmissions
+  bombe             find the settings consistent with a crib, a known part of the plaintext
+  keysheet          generate random daily keys
+  score             score a text with the n-gram model used to break messages
+  train             count the n-grams of a corpus
+Use '{} COMMAND --help' for the options of each command.";
+
+static KEY_OPTIONS : &str = "
+  --rotors III-II-I      wheel order from left to right, the greek wheel first for the
+                         M4: 'Beta VI I VIII'
+  --reflector B          B-thin or C-thin for the M4
+  --rings AAA            from left to right, AAAA for the M4
+  --positions AAA        start positions from left to right, also --key
+  --plugboard 'AV BS'    stecker pairs";
+
+static IO_OPTIONS : &str = "
+  --input FILE           read FILE rather than the standard input
+  --output FILE          write the results to FILE rather than the standard output";
+
+static FORMAT_OPTIONS : &str = "
+  --format text|json     print the results as a JSON object, with the complete keys,
+                         scores, confidence and the time taken";
+
+static BREAK_FORMAT_OPTIONS : &str = "
+  --format text|verbose|json
+                         text prints the start positions and score of each key found
+                         and its decryption, verbose the complete keys and the
+                         confidence in the decryptions, json all of them as a JSON
+                         object with the time taken";
+
+static MODEL_OPTIONS : &str = "
+  --language english|german
+  --ngram-size 2|4       score with the bigrams or the quadgrams of the language model
+  --ngrams FILE          n-gram counts to use instead of the language model
+  --floor X              log-probability of the n-grams missing from FILE";
+
+static RUN_OPTIONS : &str = "
+  --threads N
+  --budget SECONDS       stop and print the best results so far after this time, for
+                         each message of a batch job
+  --progress 5           seconds between progress reports on stderr, 0 for none";
+
+static SEARCH_OPTIONS : &str = "
+  --machine m3|m4        three rotors out of I-V with reflector B, or the naval M4 with
+                         rotors I-VIII, a greek wheel and a thin reflector; this sets
+                         the pool, reflectors and greek wheels. The m4 search tries
+                         34,944 wheel orders, reflectors, greek wheels and greek
+                         positions, 582 times the 60 wheel orders of m3
+  --rotors 'II ? V'      wheel order from left to right, ? to search a slot, the greek
+                         wheel coming first for the M4: 'Beta ? ? VI'
+  --pool 'I II III IV V' rotors to use for the searched slots
+  --greek Beta,Gamma     greek wheels to try
+  --reflector B,C        reflectors to try
+  --rings AAA            known rings, from left to right, AAAA for the M4
+  --positions AAA        known start positions, AAAA for the M4
+  --plugboard 'AV BS'    known stecker pairs";
+
+static CHECKPOINT_OPTIONS : &str = "
+  --checkpoint FILE      save the state of the search to FILE regularly
+  --resume FILE          resume the search saved in FILE, saving it there too
+  --shard K/N            only search the K-th part out of N of the wheel orders,
+                         saving the result to the checkpoint file, shard_K_of_N.txt
+                         by default";
+
+static BREAK_OPTIONS : &str = "
+  --candidates 100       candidates kept from the position search
+  --results 1            number of ranked keys to print
+  --steckers hill|anneal|restart|tabu
+                         search for the unknown stecker pairs
+  --seed 0               seed for the randomised stecker searches";
+
+// The help of each command, program being the name the binary was run with.
+fn usage(program: &str, command: &str) -> String {
+    match command {
+        "encrypt" | "decrypt" => format!("\
+Usage: {} {} [OPTIONS] [KEY RNG]
+Enciphers the input, line by line, keeping only the letters. The key defaults to rotors
+III-II-I with reflector B and everything at A. KEY and RNG are the start positions and
+the rings of a three rotor machine, RNG starting from the rightmost rotor.{}{}
+  --format text|json     print the key and the output as a JSON object
+  --trace                print the letter after each substitution, for each key
+  --input-format json    encipher a batch of messages, given as an array or one per
+                         line: {{\"id\": 1, \"text\": \"...\", \"key\": {{\"positions\": \"QEV\"}}}}, the
+                         fields of the key replacing those of the options", program, command, KEY_OPTIONS, IO_OPTIONS),
+        "break" => format!("\
+Usage: {} break [OPTIONS] [NGRAMS [FLOOR]]
+Finds the key of the message read from the input.{}{}{}
+  --input-format json    break a batch of messages, given as an array or one per line:
+                         {{\"id\": 1, \"ciphertext\": \"...\", \"options\": {{\"rotors\": \"? ? V\"}}}},
+                         the options adding to those of the command line{}{}{}{}", program, MODEL_OPTIONS, IO_OPTIONS, BREAK_FORMAT_OPTIONS, RUN_OPTIONS, SEARCH_OPTIONS, CHECKPOINT_OPTIONS, BREAK_OPTIONS),
+        "merge" => format!("\
+Usage: {} merge [OPTIONS] SHARD_FILE...
+Combines the results of break --shard, searching the wheel orders that no shard covers.
+The ciphertext and the model and search options are read from the shards, the options
+given here adding to them: only --results can differ from the shards. Shards from older
+versions do not record them, the ciphertext must then be given again as input and the
+model and search options repeated exactly.{}{}{}{}{}{}
+  --checkpoint FILE      save the merged state to FILE regularly", program, MODEL_OPTIONS, IO_OPTIONS, BREAK_FORMAT_OPTIONS, RUN_OPTIONS, SEARCH_OPTIONS, BREAK_OPTIONS),
+        "depth" => format!("\
+Usage: {} depth [OPTIONS] [NGRAMS [FLOOR]]
+Breaks the messages read from the input, one per line, sharing the same key except for
+their start positions. The checkpoint, resume, shard and positions options do not apply.{}{}{}{}{}{}{}",
+            program, MODEL_OPTIONS, IO_OPTIONS, FORMAT_OPTIONS, RUN_OPTIONS, SEARCH_OPTIONS, CHECKPOINT_OPTIONS, BREAK_OPTIONS),
+        "indicators" => format!("\
+Usage: {} indicators [OPTIONS] [NGRAMS [FLOOR]]
+Recovers the day key and the message keys from the transmissions read from the input, one
+per line as 'START INDICATOR TEXT' where the message key is enciphered at START, once
+or twice. The checkpoint, resume, shard and positions options do not apply.{}{}{}{}{}{}{}",
+            program, MODEL_OPTIONS, IO_OPTIONS, FORMAT_OPTIONS, RUN_OPTIONS, SEARCH_OPTIONS, CHECKPOINT_OPTIONS, BREAK_OPTIONS),
+        "bombe" => format!("\
+Usage: {} bombe [OPTIONS] CRIB
+Finds the wheel orders, positions and stecker pairs for which CRIB, a known part of the
+plaintext, is consistent with the message read from the input. The middle rotor is
+assumed not to move while the crib is enciphered, nor before, which may have to be
+corrected by breaking the message with the wheel order and pairs found.
+  --offset N             position of the crib in the message, all the possible ones
+                         being tried by default
+  --candidates 100       maximum number of stops to print{}{}{}", program, IO_OPTIONS, RUN_OPTIONS, SEARCH_OPTIONS),
+        "keysheet" => format!("\
+Usage: {} keysheet [OPTIONS]
+Prints random daily keys, the positions being the ground setting used for the indicators.
+  --days 31
+  --pairs 10             number of stecker pairs
+  --seed N               seed to get the same key sheet again
+  --machine m3|m4
+  --pool 'I II III IV V' rotors to choose from
+  --greek Beta,Gamma     greek wheels to choose from
+  --reflector B,C        reflectors to choose from
+  --output FILE          write the key sheet to FILE rather than the standard output", program),
+        "score" => format!("\
+Usage: {} score [OPTIONS]
+Scores the text read from the input and tells whether it looks like plaintext.{}{}", program, MODEL_OPTIONS, IO_OPTIONS),
+        "train" => format!("\
+Usage: {} train [OPTIONS] CORPUS PREFIX
+Writes the counts of the n-grams of CORPUS, for n from 1 to 5, to PREFIX_monograms.txt
+and so on. The corpus and prefix can also be given with --input and --output.
+  --umlauts              expand the umlauts and sharp s, e.g. AE for an a umlaut
+  --x-for-space          replace the spaces with X", program),
+        "interactive" => format!("\
+Usage: {} interactive [OPTIONS]
+Simulates a machine operated from the terminal, showing the rotor windows and the lamp lit
+by the last key. Each line typed is a sequence of key presses, '<' turning the rotors back
+by one key. Lines starting with ':' change the machine, see :help.{}", program, KEY_OPTIONS),
+        _ => format!("Usage: {} COMMAND [OPTIONS]\n{}", program, COMMANDS.replace("{}", program)),
+    }
+}
+
+// Prints the help of the command on stderr, returning the corresponding error.
+fn usage_error(program: &str, command: &str, message: &str) -> Error {
+    let _ = writeln!(io::stderr(), "{}", usage(program, command));
+    Error::Usage(message.to_string())
+}
+
+// Parses a space, comma or dash separated list of names, in the order given. Names
+// containing a dash, such as B-thin, are kept whole.
+fn parse_names(names: &str, from_name: fn(&str) -> Option<u8>, unknown: fn(String) -> Error) -> Result<Vec<Option<u8>>, Error> {
+    names.split(|c: char| c == ',' || c.is_whitespace()).filter(|n| !n.is_empty())
+        .flat_map(|name| if from_name(name).is_some() { vec![name] } else { name.split('-').collect() })
+        .filter(|n| !n.is_empty()).map(|name|
+            if name == "?" { Ok(None) }
+            else { from_name(name).map(Some).ok_or_else(|| unknown(name.to_string())) }
+        ).collect()
+}
+
+// Rings and positions of a three or four rotor machine, the latter starting with the
+// greek wheel.
+fn parse_rotor_letters(letters: &str, what: &'static str) -> Result<Vec<u8>, Error> {
+    let len = if letters.chars().count() == 4 { 4 } else { 3 };
+    encrypt::parse_letters(letters, len, what)
+}
+
+// Options restricting the search space, the wheel order, rings and positions being given
+// from left to right. For the M4 the greek wheel comes first.
+fn parse_search_option(spec: &mut cryptanalysis::SearchSpec, option: &str, value: &str) -> Result<(), Error> {
+    match option {
+        "--machine" => {
+            let machine = match value.to_lowercase().as_str() {
+                "m3" => cryptanalysis::SearchSpec::new(),
+                "m4" => cryptanalysis::SearchSpec::m4(),
+                _ => return Err(Error::Usage(format!("unknown machine '{}', use m3 or m4", value))),
+            };
+            spec.rotor_pool = machine.rotor_pool;
+            spec.reflectors = machine.reflectors;
+            spec.greek = machine.greek;
+        },
+        "--rotors" => {
+            let mut rotors = parse_names(value, encrypt::rotor_from_name, Error::UnknownRotor)?;
+            if rotors.len() == 4 {
+                match rotors.remove(0) {
+                    Some(greek) => spec.greek = vec![greek],
+                    None if spec.greek.is_empty() => spec.greek = encrypt::GREEK_ROTORS.to_vec(),
+                    None => (),
                 }
             }
+            if rotors.len() != 3 {
+                return Err(Error::Usage(format!("wheel order '{}' should have 3 rotors, or 4 with a greek wheel", value)));
+            }
+            spec.rotors = rotors.into_iter().rev().collect();
+        },
+        "--greek" => {
+            let greek = parse_names(value, encrypt::rotor_from_name, Error::UnknownRotor)?;
+            spec.greek = greek.into_iter().map(|r| r.ok_or_else(|| Error::Usage("'?' is not allowed for greek wheels".to_string()))).collect::<Result<_, _>>()?;
+        },
+        "--pool" => {
+            let pool = parse_names(value, encrypt::rotor_from_name, Error::UnknownRotor)?;
+            spec.rotor_pool = pool.into_iter().map(|r| r.ok_or_else(|| Error::Usage("'?' is not allowed in a pool".to_string()))).collect::<Result<_, _>>()?;
+        },
+        "--reflector" => {
+            let reflectors = parse_names(value, encrypt::reflector_from_name, Error::UnknownReflector)?;
+            spec.reflectors = reflectors.into_iter().map(|r| r.ok_or_else(|| Error::Usage("'?' is not allowed for reflectors".to_string()))).collect::<Result<_, _>>()?;
+        },
+        "--rings" => spec.rings = Some(parse_rotor_letters(value, "rings")?.into_iter().rev().collect()),
+        "--positions" => spec.positions = Some(parse_rotor_letters(value, "positions")?),
+        "--plugboard" => spec.plugboard = encrypt::parse_plugboard(value)?,
+        "--candidates" | "--results" => match value.parse() {
+            Ok(n) if 0 < n => if option == "--results" { spec.results = n } else { spec.candidates = n },
+            _ => return Err(Error::Usage(format!("invalid value for {} '{}'", option, value))),
+        },
+        "--steckers" => match cryptanalysis::Optimiser::from_name(value) {
+            Some(optimiser) => spec.steckers = Some(optimiser),
+            None => return Err(Error::Usage(format!("unknown stecker search '{}', use hill, anneal, restart or tabu", value))),
+        },
+        "--seed" => match value.parse() {
+            Ok(seed) => spec.seed = seed,
+            Err(_) => return Err(Error::Usage(format!("invalid seed '{}'", value))),
         },
-        Err(_) => ()
+        otherwise => return Err(Error::Usage(format!("unrecognized option {}", otherwise))),
     }
+    Ok(())
 }
 
-fn main() {
-    let args = os::args();
-    if args.len() < 2 {
-        println!("Usage: {} encrypt|break", args[0]);
-        return;
+// Prints the progress on stderr, at most once per interval and at the end of each stage.
+fn report_progress(progress: &cryptanalysis::Progress, interval: Option<Duration>, last_report: &Mutex<Instant>) {
+    let interval = match interval {
+        None => return,
+        Some(interval) => interval,
+    };
+    let mut last_report = last_report.lock().unwrap();
+    if last_report.elapsed() < interval && progress.done < progress.total { return; }
+    *last_report = Instant::now();
+    let stage = match progress.stage {
+        cryptanalysis::Stage::Wheels => "wheel orders",
+        cryptanalysis::Stage::Keys => "candidates",
+    };
+    let mut line = format!("[{:.1}s] {} {}/{}", progress.elapsed.as_secs_f64(), stage, progress.done, progress.total);
+    if let Some(eta) = progress.eta() {
+        line.push_str(&format!(", eta {:.0}s", eta.as_secs_f64()));
+    }
+    if let Some((score, ref key)) = progress.best {
+        line.push_str(&format!(", best {} {:.2}", key, score));
+    }
+    let _ = writeln!(io::stderr(), "{}", line);
+}
+
+// The n-gram model used to score decryptions, given by a language or a file of counts.
+struct ModelOptions {
+    language: ngram::Language,
+    // The n-grams of the language, 2 or 4.
+    size: usize,
+    ngrams: Option<String>,
+    floor: Option<String>,
+}
+
+fn parse_model_option(options: &mut ModelOptions, option: &str, value: &str) -> Result<bool, Error> {
+    match option {
+        "--language" => match ngram::Language::from_name(value) {
+            Some(language) => options.language = language,
+            None => return Err(Error::Usage(format!("unknown language '{}', use english or german", value))),
+        },
+        "--ngram-size" => match value {
+            "2" => options.size = 2,
+            "4" => options.size = 4,
+            _ => return Err(Error::Usage(format!("invalid n-gram size '{}', use 2 or 4", value))),
+        },
+        "--ngrams" => options.ngrams = Some(value.to_string()),
+        "--floor" => options.floor = Some(value.to_string()),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+impl ModelOptions {
+    fn new() -> ModelOptions {
+        ModelOptions { language: ngram::Language::English, size: 4, ngrams: None, floor: None }
+    }
+
+    fn load(&self) -> Result<ngram::NGramModel, Error> {
+        let floor = match self.floor {
+            None => None,
+            Some(ref floor) => match floor.parse() {
+                Ok(floor) => Some(floor),
+                Err(_) => return Err(Error::Usage(format!("floor '{}' is not a number", floor))),
+            },
+        };
+        match self.ngrams {
+            None => Ok(self.language.ngrams(self.size).unwrap()),
+            Some(ref path) => ngram::NGramModel::from_file(path, floor),
+        }
+    }
+}
+
+// How a search is run: the number of threads, when to stop it and how often to report
+// its progress.
+struct RunOptions {
+    threads: usize,
+    budget: Option<Duration>,
+    progress: Option<Duration>,
+}
+
+fn parse_run_option(options: &mut RunOptions, option: &str, value: &str) -> Result<bool, Error> {
+    match option {
+        "--threads" => match value.parse() {
+            Ok(threads) if 0 < threads => options.threads = threads,
+            _ => return Err(Error::Usage(format!("invalid number of threads '{}'", value))),
+        },
+        "--budget" | "--progress" => match value.parse::<f64>() {
+            Ok(secs) if 0. <= secs && secs.is_finite() => {
+                let duration = Duration::from_secs_f64(secs);
+                if option == "--budget" { options.budget = Some(duration) }
+                else { options.progress = if secs == 0. { None } else { Some(duration) } }
+            },
+            _ => return Err(Error::Usage(format!("invalid number of seconds '{}'", value))),
+        },
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+impl RunOptions {
+    fn new() -> RunOptions {
+        RunOptions { threads: cryptanalysis::default_threads(), budget: None, progress: Some(Duration::from_secs(5)) }
+    }
+}
+
+// Options of the commands that set a machine, the wheel order, rings and positions being
+// given from left to right. For the M4 the greek wheel comes first and the reflector
+// defaults to B-thin.
+#[derive(Clone)]
+struct KeyOptions {
+    rotors: Option<Vec<u8>>,
+    reflector: Option<u8>,
+    rings: Option<Vec<u8>>,
+    positions: Option<Vec<u8>>,
+    plugboard: Vec<u8>,
+}
+
+fn parse_key_option(options: &mut KeyOptions, option: &str, value: &str) -> Result<bool, Error> {
+    match option {
+        "--rotors" => {
+            let rotors = parse_names(value, encrypt::rotor_from_name, Error::UnknownRotor)?;
+            let rotors: Vec<u8> = rotors.into_iter().map(|r| r.ok_or_else(|| Error::Usage("'?' is only allowed when breaking".to_string()))).collect::<Result<_, _>>()?;
+            if rotors.len() != 3 && rotors.len() != 4 {
+                return Err(Error::Usage(format!("wheel order '{}' should have 3 rotors, or 4 with a greek wheel", value)));
+            }
+            options.rotors = Some(rotors.into_iter().rev().collect());
+        },
+        "--reflector" => match encrypt::reflector_from_name(value) {
+            Some(reflector) => options.reflector = Some(reflector),
+            None => return Err(Error::UnknownReflector(value.to_string())),
+        },
+        "--rings" => options.rings = Some(parse_rotor_letters(value, "rings")?.into_iter().rev().collect()),
+        "--positions" | "--key" => options.positions = Some(parse_rotor_letters(value, "positions")?),
+        "--plugboard" => options.plugboard = encrypt::parse_plugboard(value)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+impl KeyOptions {
+    fn new() -> KeyOptions {
+        KeyOptions { rotors: None, reflector: None, rings: None, positions: None, plugboard: encrypt::identity_plugboard() }
     }
-    match args[1].as_slice() {
-        "encrypt" => main_encrypt(&args),
-        "break" => main_break(&args),
-        otherwise => println!("Unrecognized argument {}, use encrypt or break", otherwise),
+
+    // The default machine is rotors III-II-I with reflector B, or B-thin with four rotors,
+    // and the rings and positions at A.
+    fn key(self) -> Result<encrypt::Key, Error> {
+        let rotors = self.rotors.unwrap_or(vec![0u8, 1, 2]);
+        let n = rotors.len();
+        let key = encrypt::Key {
+            reflector: self.reflector.unwrap_or(if n == 4 { encrypt::THIN_REFLECTORS[0] } else { 1 }),
+            rings: self.rings.unwrap_or(vec![0u8; n]),
+            positions: self.positions.unwrap_or(vec![0u8; n]),
+            plugboard: self.plugboard,
+            rotors,
+        };
+        key.check()?;
+        Ok(key)
     }
 }
 
+// The key of a batch message: the one of the command line with the fields of the "key"
+// object of the message, if any, e.g. {"positions": "QEV", "plugboard": "AV BS"}.
+fn message_key(options: &KeyOptions, message: &Json) -> Result<encrypt::Key, Error> {
+    let mut options = options.clone();
+    for (name, value) in message_fields(message, "key")? {
+        if !parse_key_option(&mut options, &format!("--{}", name), &option_value(name, value)?)? {
+            return Err(Error::Json(format!("unknown key field '{}'", name)));
+        }
+    }
+    options.key()
+}
+
+fn letters(letters: &[u8]) -> String {
+    letters.iter().map(|&c| encrypt::chr(c)).collect()
+}
+
+// The path of each letter through the machine as a table, with a column per substitution.
+fn print_trace(out: &mut Output, key: &encrypt::Key, traces: &[encrypt::Trace]) -> Result<(), Error> {
+    let names: Vec<&str> = key.rotors.iter().map(|&r| encrypt::rotor_name(r)).collect();
+    let mut header = vec!["Key", "Windows", "Plugboard"];
+    header.extend(names.iter());
+    header.push(encrypt::reflector_name(key.reflector));
+    header.extend(names.iter().rev());
+    header.push("Lamp");
+    let rows: Vec<Vec<String>> = traces.iter().map(|trace| {
+        let mut row = vec![letters(&[trace.input]), letters(&trace.positions), letters(&[trace.plugboard])];
+        row.extend(trace.forward.iter().map(|&c| letters(&[c])));
+        row.push(letters(&[trace.reflector]));
+        row.extend(trace.backward.iter().map(|&c| letters(&[c])));
+        row.push(letters(&[trace.output]));
+        row
+    }).collect();
+    let widths: Vec<usize> = header.iter().enumerate().map(|(idx, name)|
+        rows.iter().map(|row| row[idx].len()).fold(name.len(), cmp::max)).collect();
+    let line = |cells: Vec<&str>| -> String {
+        cells.iter().zip(widths.iter()).map(|(cell, &width)| format!("{:width$}", cell, width = width)).collect::<Vec<String>>().join("  ").trim_end().to_string()
+    };
+    out.line(format_args!("{}", line(header.clone())))?;
+    for row in rows.iter() {
+        out.line(format_args!("{}", line(row.iter().map(|cell| cell.as_str()).collect())))?;
+    }
+    Ok(())
+}
+
+fn trace_json(trace: &encrypt::Trace) -> Json {
+    Json::object(vec![
+        ("key", Json::String(letters(&[trace.input]))),
+        ("positions", Json::String(letters(&trace.positions))),
+        ("plugboard", Json::String(letters(&[trace.plugboard]))),
+        ("forward", Json::String(letters(&trace.forward))),
+        ("reflector", Json::String(letters(&[trace.reflector]))),
+        ("backward", Json::String(letters(&trace.backward))),
+        ("lamp", Json::String(letters(&[trace.output]))),
+    ])
+}
+
+// Prints the encryption of text with key, or with trace the path of each of its letters
+// through the machine.
+fn print_encryption(out: &mut Output, format: Format, id: Option<Json>, world: &encrypt::World, key: &encrypt::Key, text: &str, trace: bool) -> Result<(), Error> {
+    let lines = encrypt::encrypt_lines(world, key, text)?;
+    let traces = if trace { encrypt::trace_key(world, key, &encrypt::input_to_u8(text))? } else { Vec::new() };
+    match format {
+        Format::Json => {
+            let mut fields: Vec<(&str, Json)> = id.map(|id| vec![("id", id)]).unwrap_or_default();
+            fields.push(("key", key_json(key)));
+            fields.push(("text", Json::String(lines.join("\n"))));
+            if trace { fields.push(("trace", Json::Array(traces.iter().map(trace_json).collect()))); }
+            return out.line(format_args!("{}", Json::object(fields)));
+        },
+        _ if trace => return print_trace(out, key, &traces),
+        Format::Text | Format::Verbose => for line in lines.iter() {
+            out.line(format_args!("{}", line))?;
+        },
+    }
+    Ok(())
+}
+
+// Encrypts the input line by line, the letters of each line being enciphered as one
+// message so that a message split over several lines gives the same result as on a
+// single line. With JSON input, each message is enciphered with its own key.
+fn main_encrypt(program: &str, command: &str, args: &[String]) -> Result<(), Error> {
+    let args = parse_args(args, &["--trace"])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, command));
+        return Ok(());
+    }
+    let mut options = KeyOptions::new();
+    let mut input_path = None;
+    let mut output_path = None;
+    let mut format = Format::Text;
+    let mut input_format = Format::Text;
+    for &(option, value) in args.options.iter() {
+        match option {
+            "--input" => input_path = Some(value),
+            "--output" => output_path = Some(value),
+            "--format" => format = parse_format(value)?,
+            "--input-format" => input_format = parse_input_format(value)?,
+            _ if parse_key_option(&mut options, option, value)? => (),
+            otherwise => return Err(Error::Usage(format!("unrecognized option {}", otherwise))),
+        }
+    }
+    match args.positional.len() {
+        0 => (),
+        // The original form: the positions and the rings of rotors III-II-I, the rings
+        // starting from the rightmost rotor.
+        2 => {
+            options.positions = Some(encrypt::parse_letters(args.positional[0], 3, "key")?);
+            options.rings = Some(encrypt::parse_letters(args.positional[1], 3, "rings")?);
+        },
+        _ => return Err(usage_error(program, command, &format!("{} takes either options or a key and rings", command))),
+    }
+    let input = read_input(input_path)?;
+    let world = encrypt::world();
+    let mut out = Output::open(output_path)?;
+    if input_format == Format::Text {
+        let key = options.key()?;
+        print_encryption(&mut out, format, None, &world, &key, &input, args.flag("--trace"))?;
+        return out.close();
+    }
+    let mut first_error = None;
+    for (idx, message) in read_messages(&input)?.iter().enumerate() {
+        let id = message_id(message, idx);
+        let res = message_key(&options, message).and_then(|key| Ok((key, message_text(message, "text")?)));
+        match res {
+            Ok((key, text)) => print_encryption(&mut out, format, Some(id), &world, &key, text, args.flag("--trace"))?,
+            Err(err) => {
+                report_message_error(&mut out, format, id, &err)?;
+                first_error = first_error.or(Some(err));
+            },
+        }
+    }
+    out.close()?;
+    first_error.map_or(Ok(()), Err)
+}
+
+static INTERACTIVE_HELP : &str = "\
+  :rotors II IV V      change the wheel order, from left to right
+  :reflector C
+  :rings AAB           change the rings, from left to right
+  :positions QEV       turn the rotors to these positions, also :key
+  :plugboard AV BS     replace all the stecker pairs, none when empty
+  :plug AV             add a stecker pair
+  :unplug A            remove the pair of a letter
+  :back N              undo the last N key presses, 1 by default
+  :quit";
+
+// The options giving key, so that the interactive commands can change one component.
+fn key_options(key: &encrypt::Key) -> KeyOptions {
+    KeyOptions {
+        rotors: Some(key.rotors.clone()),
+        reflector: Some(key.reflector),
+        rings: Some(key.rings.clone()),
+        positions: Some(key.positions.clone()),
+        plugboard: key.plugboard.clone(),
+    }
+}
+
+// Applies a command of the interactive mode, e.g. ":plug AV", to the machine. Returns the
+// number of key presses undone, for the tape.
+fn interactive_command(machine: &mut encrypt::Machine, command: &str, value: &str) -> Result<usize, Error> {
+    let key = machine.key();
+    let mut options = key_options(&key);
+    match command {
+        "back" => {
+            let count = if value.is_empty() { 1 } else {
+                value.parse().map_err(|_| Error::Usage(format!("invalid number of key presses '{}'", value)))?
+            };
+            return Ok((0..count).take_while(|_| machine.backspace()).count());
+        },
+        "plug" => {
+            let pairs = format!("{} {}", encrypt::plugboard_pairs(&key.plugboard), value);
+            options.plugboard = encrypt::parse_plugboard(&pairs)?;
+        },
+        "unplug" => {
+            let letter = encrypt::parse_letters(value, 1, "letter")?[0] as usize;
+            let partner = options.plugboard[letter] as usize;
+            options.plugboard[letter] = letter as u8;
+            options.plugboard[partner] = partner as u8;
+        },
+        "rotors" => {
+            parse_key_option(&mut options, "--rotors", value)?;
+            // With a greek wheel added or removed, the machine is set up anew.
+            if options.rotors.as_ref().map(|rotors| rotors.len()) != Some(key.rotors.len()) {
+                options.reflector = None;
+                options.rings = None;
+                options.positions = None;
+            }
+        },
+        "plugboard" if value.is_empty() => options.plugboard = encrypt::identity_plugboard(),
+        "reflector" | "rings" | "positions" | "key" | "plugboard" => {
+            parse_key_option(&mut options, &format!("--{}", command), value)?;
+        },
+        _ => return Err(Error::Usage(format!("unknown command :{}, see :help", command))),
+    }
+    machine.set_key(options.key()?)?;
+    Ok(0)
+}
+
+// The rotors with their windows and the lampboard, lit being the lamp of the last key.
+fn print_machine(machine: &encrypt::Machine, lit: Option<u8>, tape: &[u8]) {
+    let key = machine.key();
+    let names: Vec<String> = key.rotors.iter().rev().map(|&r| format!("{:^7}", encrypt::rotor_name(r))).collect();
+    let windows: Vec<String> = key.positions.iter().map(|&p| format!("{:^7}", format!("[ {} ]", encrypt::chr(p)))).collect();
+    let rings: String = key.rings.iter().rev().map(|&r| encrypt::chr(r)).collect();
+    let plugboard = encrypt::plugboard_pairs(&key.plugboard);
+    println!();
+    println!("{}   reflector {}", names.concat(), encrypt::reflector_name(key.reflector));
+    println!("{}   rings {}, plugboard {}", windows.concat(), rings, if plugboard.is_empty() { "-" } else { &plugboard });
+    println!();
+    for (indent, row) in [(0, "QWERTZUIO"), (2, "ASDFGHJK"), (0, "PYXCVBNML")] {
+        let lamps: Vec<String> = row.chars().map(|c|
+            if lit.map(encrypt::chr) == Some(c) { format!("({})", c) } else { format!(" {} ", c) }).collect();
+        println!("{}{}", " ".repeat(indent), lamps.join(" ").trim_end());
+    }
+    let groups: Vec<String> = tape.chunks(5).map(letters).collect();
+    println!();
+    println!("Tape: {}", groups.join(" "));
+}
+
+// A line oriented simulator: each line is a sequence of key presses, so that it also
+// works when the input is not a terminal, e.g. for scripted demonstrations.
+fn main_interactive(program: &str, args: &[String]) -> Result<(), Error> {
+    let args = parse_args(args, &[])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, "interactive"));
+        return Ok(());
+    }
+    let mut options = KeyOptions::new();
+    for &(option, value) in args.options.iter() {
+        if !parse_key_option(&mut options, option, value)? {
+            return Err(Error::Usage(format!("unrecognized option {}", option)));
+        }
+    }
+    if !args.positional.is_empty() {
+        return Err(usage_error(program, "interactive", "interactive only takes options"));
+    }
+    let mut machine = encrypt::Machine::new(options.key()?)?;
+    let mut tape = Vec::new();
+    let mut lit = None;
+    println!("Type letters to encipher them, '<' to undo the last one, :help for the commands.");
+    print_machine(&machine, lit, &tape);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) => return Err(Error::Io { path: "stdin".to_string(), message: err.to_string() }),
+        }
+        let line = line.trim();
+        if let Some(command) = line.strip_prefix(':') {
+            let (command, value) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+            match command {
+                "quit" | "q" => break,
+                "help" => { println!("{}", INTERACTIVE_HELP); continue; },
+                _ => match interactive_command(&mut machine, command, value.trim()) {
+                    Ok(undone) => {
+                        tape.truncate(tape.len() - cmp::min(undone, tape.len()));
+                        lit = None;
+                    },
+                    Err(err) => {
+                        let _ = writeln!(io::stderr(), "Error: {}", err);
+                        continue;
+                    },
+                },
+            }
+        }
+        else {
+            for c in line.chars() {
+                if c == '<' {
+                    if machine.backspace() { tape.pop(); }
+                    lit = None;
+                }
+                else if let Some(letter) = encrypt::ord(c) {
+                    let lamp = machine.press(letter)?;
+                    tape.push(lamp);
+                    lit = Some(lamp);
+                }
+            }
+        }
+        print_machine(&machine, lit, &tape);
+    }
+    Ok(())
+}
+
+// Parses K/N, returning the 0-based shard index and the number of shards.
+fn parse_shard(value: &str) -> Option<(usize, usize)> {
+    let mut parts = value.splitn(2, '/');
+    match (parts.next().map(|k| k.parse()), parts.next().map(|n| n.parse())) {
+        (Some(Ok(k)), Some(Ok(n))) if 1usize <= k && k <= n => Some((k - 1, n)),
+        _ => None,
+    }
+}
+
+const CHECKPOINT_INTERVAL : Duration = Duration::from_secs(10);
+
+fn read_checkpoint(path: &str) -> Result<cryptanalysis::Checkpoint, Error> {
+    let input = read_input(Some(path))?;
+    cryptanalysis::Checkpoint::parse(&input).map_err(|err| match err {
+        Error::Checkpoint(message) => Error::Checkpoint(format!("'{}' {}", path, message)),
+        err => err,
+    })
+}
+
+// The checkpoint is written to a temporary file first so that the previous one is kept
+// if the process is killed while writing.
+fn write_checkpoint(path: &str, checkpoint: &cryptanalysis::Checkpoint) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    File::create(&tmp_path).and_then(|mut file| file.write_all(checkpoint.to_string().as_bytes()))?;
+    fs::rename(&tmp_path, path)
+}
+
+fn print_confidence(out: &mut Output, confidence: &cryptanalysis::Confidence) -> Result<(), Error> {
+    out.line(format_args!("z-score {:.1} IC {:.4}", confidence.z_score, confidence.ic))?;
+    if !confidence.broken {
+        out.line(format_args!("Unbroken: the decryption scores like random text, the key is probably wrong."))?;
+    }
+    Ok(())
+}
+
+fn report_interrupted() -> bool {
+    let interrupted = interrupt::INTERRUPTED.load(Ordering::SeqCst);
+    if interrupted {
+        let _ = writeln!(io::stderr(), "Search stopped early, these are the best keys found so far.");
+    }
+    interrupted
+}
+
+// The result of a search in JSON, id being the one of the message in a batch job.
+fn search_json(id: Option<Json>, solutions: Vec<Json>, elapsed: Duration, interrupted: bool) -> Json {
+    let mut fields: Vec<(&str, Json)> = id.map(|id| vec![("id", id)]).unwrap_or_default();
+    fields.push(("solutions", Json::Array(solutions)));
+    fields.push(("elapsed", Json::Number(elapsed.as_secs_f64())));
+    fields.push(("interrupted", Json::Bool(interrupted)));
+    Json::object(fields)
+}
+
+// Breaks the messages, one per line, and prints the keys found for each of them.
+fn break_depth(input: &str, out: &mut Output, format: Format, model: &ngram::NGramModel, spec: &cryptanalysis::SearchSpec, threads: usize, control: &cryptanalysis::Control) -> Result<(), Error> {
+    let messages: Vec<&str> = input.lines().filter(|line| !encrypt::input_to_u8(line).is_empty()).collect();
+    interrupt::install();
+    let start = Instant::now();
+    let solutions = cryptanalysis::brute_force_depth(&messages, model, spec, threads, control)?;
+    let interrupted = report_interrupted();
+    if format == Format::Json {
+        let solutions = solutions.iter().map(|solution| Json::object(vec![
+            ("score", Json::Number(solution.score)),
+            ("confidence", confidence_json(&solution.confidence)),
+            ("messages", Json::Array(solution.keys.iter().zip(solution.plaintexts.iter()).map(|(key, plaintext)|
+                Json::object(vec![("key", key_json(key)), ("plaintext", Json::string(plaintext))])).collect())),
+        ])).collect();
+        return out.line(format_args!("{}", search_json(None, solutions, start.elapsed(), interrupted)));
+    }
+    if solutions.is_empty() {
+        out.line(format_args!("No optimal key found."))?;
+    }
+    for (rank, solution) in solutions.iter().enumerate() {
+        let rank = if 1 < solutions.len() { format!("{:3} ", rank + 1) } else { String::new() };
+        out.line(format_args!("{}{}", rank, solution.score))?;
+        for (key, plaintext) in solution.keys.iter().zip(solution.plaintexts.iter()) {
+            out.line(format_args!("{} {}", key, plaintext))?;
+        }
+        print_confidence(out, &solution.confidence)?;
+    }
+    Ok(())
+}
+
+fn break_indicators(input: &str, out: &mut Output, format: Format, model: &ngram::NGramModel, spec: &cryptanalysis::SearchSpec, threads: usize, control: &cryptanalysis::Control) -> Result<(), Error> {
+    let transmissions = input.lines().filter(|line| !line.trim().is_empty())
+        .map(cryptanalysis::Transmission::parse).collect::<Result<Vec<_>, _>>()?;
+    interrupt::install();
+    let start = Instant::now();
+    let solutions = cryptanalysis::brute_force_indicators(&transmissions, model, spec, threads, control)?;
+    let interrupted = report_interrupted();
+    if format == Format::Json {
+        let solutions = solutions.iter().map(|solution| Json::object(vec![
+            ("score", Json::Number(solution.score)),
+            ("day_key", key_json(&solution.day_key)),
+            ("confidence", confidence_json(&solution.confidence)),
+            ("messages", Json::Array(solution.message_keys.iter().zip(solution.plaintexts.iter()).map(|(message_key, plaintext)|
+                Json::object(vec![("message_key", Json::String(letters(message_key))), ("plaintext", Json::string(plaintext))])).collect())),
+        ])).collect();
+        return out.line(format_args!("{}", search_json(None, solutions, start.elapsed(), interrupted)));
+    }
+    if solutions.is_empty() {
+        out.line(format_args!("No day key found."))?;
+    }
+    for (rank, solution) in solutions.iter().enumerate() {
+        let rank = if 1 < solutions.len() { format!("{:3} ", rank + 1) } else { String::new() };
+        out.line(format_args!("{}{} {}", rank, solution.day_key.setting(), solution.score))?;
+        for (message_key, plaintext) in solution.message_keys.iter().zip(solution.plaintexts.iter()) {
+            out.line(format_args!("{} {}", letters(message_key), plaintext))?;
+        }
+        print_confidence(out, &solution.confidence)?;
+    }
+    Ok(())
+}
+
+fn print_solutions(out: &mut Output, format: Format, id: Option<Json>, solutions: &[cryptanalysis::Solution], elapsed: Duration, interrupted: bool) -> Result<(), Error> {
+    if format == Format::Json {
+        let solutions = solutions.iter().map(|solution| Json::object(vec![
+            ("key", key_json(&solution.key)),
+            ("score", Json::Number(solution.score)),
+            ("confidence", confidence_json(&solution.confidence)),
+            ("plaintext", Json::string(&solution.plaintext)),
+        ])).collect();
+        return out.line(format_args!("{}", search_json(id, solutions, elapsed, interrupted)));
+    }
+    if let Some(id) = id {
+        out.line(format_args!("Message {}", id.as_str().map_or_else(|| id.to_string(), |id| id.to_string())))?;
+    }
+    if solutions.is_empty() {
+        out.line(format_args!("No optimal key found."))?;
+    }
+    else if format == Format::Text {
+        for solution in solutions.iter() {
+            out.line(format_args!("{} {}", letters(&solution.key.positions), solution.score))?;
+            out.line(format_args!("{}", solution.plaintext))?;
+        }
+        if !solutions[0].confidence.broken {
+            let _ = writeln!(io::stderr(), "Unbroken: the decryption scores like random text, the key is probably wrong.");
+        }
+    }
+    else if solutions.len() == 1 {
+        out.line(format_args!("{} {}", solutions[0].key, solutions[0].score))?;
+        out.line(format_args!("{}", solutions[0].plaintext))?;
+        print_confidence(out, &solutions[0].confidence)?;
+    }
+    else {
+        for (rank, solution) in solutions.iter().enumerate() {
+            let preview: String = solution.plaintext.chars().take(40).collect();
+            out.line(format_args!("{:3} {:.2} z {:.1} {} {}{}", rank + 1, solution.score, solution.confidence.z_score, solution.key, preview,
+                                  if solution.confidence.broken { "" } else { " (unbroken)" }))?;
+        }
+    }
+    Ok(())
+}
+
+// Breaks the messages of a batch job one after the other, each with the options of the
+// command line and those of its "options" object, e.g. {"rotors": "? ? V"}. The budget
+// applies to each message, while Ctrl-C stops the current one and skips the remaining
+// ones, which are reported as errors.
+fn break_batch(input: &str, out: &mut Output, format: Format, model: &ngram::NGramModel, spec: &cryptanalysis::SearchSpec, threads: usize, control: &cryptanalysis::Control) -> Result<(), Error> {
+    let mut first_error = None;
+    interrupt::install();
+    for (idx, message) in read_messages(input)?.iter().enumerate() {
+        let id = message_id(message, idx);
+        if interrupt::SIGNALLED.load(Ordering::SeqCst) {
+            let err = Error::Search("interrupted before this message was searched".to_string());
+            report_message_error(out, format, id, &err)?;
+            first_error = first_error.or(Some(err));
+            continue;
+        }
+        interrupt::clear_budget_stop();
+        let start = Instant::now();
+        let res = message_fields(message, "options").and_then(|options| {
+            let mut spec = spec.clone();
+            for (name, value) in options {
+                parse_search_option(&mut spec, &format!("--{}", name), &option_value(name, value)?)?;
+            }
+            cryptanalysis::brute_force(message_text(message, "ciphertext")?, model, &spec, threads, control)
+        });
+        match res {
+            Ok(solutions) => {
+                let interrupted = report_interrupted();
+                print_solutions(out, format, Some(id), &solutions, start.elapsed(), interrupted)?;
+            },
+            Err(err) => {
+                report_message_error(out, format, id, &err)?;
+                first_error = first_error.or(Some(err));
+            },
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+// Also handles merge, which takes shard files instead of the n-grams as positional
+// arguments, depth which reads several messages sharing the same key and indicators
+// which reads complete transmissions.
+fn main_break(program: &str, command: &str, args: &[String]) -> Result<(), Error> {
+    let merge = command == "merge";
+    let depth = command == "depth";
+    let indicators = command == "indicators";
+    let args = parse_args(args, &[])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, command));
+        return Ok(());
+    }
+    let mut model_options = ModelOptions::new();
+    let mut run_options = RunOptions::new();
+    let mut shard = None;
+    let mut checkpoint_path = None;
+    let mut resume_path = None;
+    let mut input_path = None;
+    let mut output_path = None;
+    let mut format = Format::Text;
+    let mut input_format = Format::Text;
+    let mut spec = cryptanalysis::SearchSpec::new();
+    // The shards record the ciphertext and the model and search options they were made
+    // with, the options given to merge coming after them.
+    let shards = if merge { args.positional.iter().map(|path| read_checkpoint(path)).collect::<Result<Vec<_>, _>>()? } else { Vec::new() };
+    let shard_options = shards.first().map_or(&[][..], |shard| shard.options());
+    let shard_options = shard_options.iter().map(|(option, value)| (option.as_str(), value.as_str()));
+    let mut recorded = Vec::new();
+    for (option, value) in shard_options.chain(args.options.iter().copied()) {
+        match option {
+            "--checkpoint" => checkpoint_path = Some(value.to_string()),
+            "--resume" => resume_path = Some(value.to_string()),
+            "--input" => input_path = Some(value),
+            "--output" => output_path = Some(value),
+            "--format" => format = parse_format(value)?,
+            "--input-format" if command == "break" => input_format = parse_input_format(value)?,
+            "--shard" if !merge && !depth && !indicators => match parse_shard(value) {
+                Some((index, count)) => shard = Some((index, count)),
+                None => return Err(Error::Usage(format!("invalid shard '{}', use K/N with K between 1 and N", value))),
+            },
+            _ if parse_model_option(&mut model_options, option, value)? => recorded.push((option.to_string(), value.to_string())),
+            _ if parse_run_option(&mut run_options, option, value)? => (),
+            _ => {
+                parse_search_option(&mut spec, option, value)?;
+                recorded.push((option.to_string(), value.to_string()));
+            },
+        }
+    }
+    let positional = &args.positional;
+    if (!merge && 2 < positional.len()) || (merge && (positional.is_empty() || resume_path.is_some())) {
+        return Err(usage_error(program, command, &format!("invalid arguments for {}", command)));
+    }
+    if input_format == Format::Json && (shard.is_some() || checkpoint_path.is_some() || resume_path.is_some()) {
+        return Err(Error::Usage("batch jobs cannot be sharded or checkpointed".to_string()));
+    }
+    if !merge {
+        model_options.ngrams = positional.first().map(|path| path.to_string()).or(model_options.ngrams);
+        model_options.floor = positional.get(1).map(|floor| floor.to_string()).or(model_options.floor);
+        for (option, value) in ["--ngrams", "--floor"].iter().zip(positional.iter()) {
+            recorded.push((option.to_string(), value.to_string()));
+        }
+    }
+    let model = model_options.load()?;
+    if let Some((index, count)) = shard {
+        if checkpoint_path.is_none() && resume_path.is_none() {
+            checkpoint_path = Some(format!("shard_{}_of_{}.txt", index + 1, count));
+        }
+    }
+    let resume = match resume_path {
+        None => None,
+        Some(ref path) => Some(read_checkpoint(path)?),
+    };
+    let resume = if !merge { resume } else {
+        let merged = cryptanalysis::merge_checkpoints(&shards, &spec)?;
+        if 0 < merged.remaining() {
+            let _ = writeln!(io::stderr(), "{} wheel orders are not covered by the shards, searching them now.", merged.remaining());
+        }
+        Some(merged)
+    };
+    let input = match shards.first() {
+        Some(shard) if input_path.is_none() && !shard.ciphertext().is_empty() => shard.ciphertext().to_string(),
+        _ => read_input(input_path)?,
+    };
+    let checkpoint_path = checkpoint_path.or(resume_path);
+    let checkpoint = |checkpoint: &cryptanalysis::Checkpoint| {
+        if let Some(ref path) = checkpoint_path {
+            if let Err(err) = write_checkpoint(path, &checkpoint.clone().with_options(recorded.clone())) {
+                let _ = writeln!(io::stderr(), "Cannot write checkpoint '{}': {}", path, err);
+            }
+        }
+    };
+    let last_report = Mutex::new(Instant::now());
+    let observer = |progress: &cryptanalysis::Progress| report_progress(progress, run_options.progress, &last_report);
+    let control = cryptanalysis::Control {
+        observer: &observer, budget: run_options.budget, stop: &interrupt::INTERRUPTED,
+        checkpoint: &checkpoint, checkpoint_interval: checkpoint_path.as_ref().map(|_| CHECKPOINT_INTERVAL),
+        resume: resume.as_ref(),
+    };
+    let mut out = Output::open(output_path)?;
+    let threads = run_options.threads;
+    let res = if depth {
+        break_depth(&input, &mut out, format, &model, &spec, threads, &control)
+    }
+    else if indicators {
+        break_indicators(&input, &mut out, format, &model, &spec, threads, &control)
+    }
+    else if input_format == Format::Json {
+        break_batch(&input, &mut out, format, &model, &spec, threads, &control)
+    }
+    else if let Some((index, count)) = shard {
+        interrupt::install();
+        cryptanalysis::search_shard(&input, &model, &spec, index, count, threads, &control).and_then(|state| {
+            let interrupted = interrupt::INTERRUPTED.load(Ordering::SeqCst);
+            let path = checkpoint_path.as_ref().unwrap();
+            if format == Format::Json {
+                let best = state.best().map_or(Json::Null, |(score, key)| Json::object(vec![("key", key_json(key)), ("score", Json::Number(score))]));
+                return out.line(format_args!("{}", Json::object(vec![
+                    ("shard", Json::String(format!("{}/{}", index + 1, count))), ("checkpoint", Json::string(path)),
+                    ("interrupted", Json::Bool(interrupted)), ("best", best),
+                ])));
+            }
+            out.line(format_args!("Shard {}/{} written to {}", index + 1, count, path))?;
+            if interrupted {
+                out.line(format_args!("The search was stopped, use --resume to complete it"))?;
+            }
+            if let Some((score, key)) = state.best() {
+                out.line(format_args!("Best candidate so far: {} {}", key, score))?;
+            }
+            Ok(())
+        })
+    }
+    else {
+        interrupt::install();
+        let start = Instant::now();
+        cryptanalysis::brute_force(&input, &model, &spec, threads, &control).and_then(|solutions| {
+            let interrupted = report_interrupted();
+            print_solutions(&mut out, format, None, &solutions, start.elapsed(), interrupted)
+        })
+    };
+    // What was written before an error is still flushed, e.g. the results of the other
+    // messages of a batch job.
+    out.close()?;
+    res
+}
+
+// Runs the bombe on the message read from the input, printing the settings at which the
+// crib is consistent with it.
+fn main_bombe(program: &str, args: &[String]) -> Result<(), Error> {
+    let args = parse_args(args, &[])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, "bombe"));
+        return Ok(());
+    }
+    let mut run_options = RunOptions::new();
+    let mut offset = None;
+    let mut input_path = None;
+    let mut output_path = None;
+    let mut spec = cryptanalysis::SearchSpec::new();
+    for &(option, value) in args.options.iter() {
+        match option {
+            "--offset" => match value.parse() {
+                Ok(n) => offset = Some(n),
+                Err(_) => return Err(Error::Usage(format!("invalid offset '{}'", value))),
+            },
+            "--input" => input_path = Some(value),
+            "--output" => output_path = Some(value),
+            _ if parse_run_option(&mut run_options, option, value)? => (),
+            _ => parse_search_option(&mut spec, option, value)?,
+        }
+    }
+    if args.positional.len() != 1 {
+        return Err(usage_error(program, "bombe", "bombe takes a crib"));
+    }
+    let last_report = Mutex::new(Instant::now());
+    let observer = |progress: &cryptanalysis::Progress| report_progress(progress, run_options.progress, &last_report);
+    let control = cryptanalysis::Control {
+        observer: &observer, budget: run_options.budget, .. cryptanalysis::Control::unlimited(&interrupt::INTERRUPTED)
+    };
+    let input = read_input(input_path)?;
+    let mut out = Output::open(output_path)?;
+    interrupt::install();
+    let stops = cryptanalysis::bombe(&input, args.positional[0], offset, &spec, run_options.threads, &control)?;
+    if interrupt::INTERRUPTED.load(Ordering::SeqCst) {
+        let _ = writeln!(io::stderr(), "Search stopped early, these are the stops found so far.");
+    }
+    if stops.is_empty() {
+        out.line(format_args!("No stop found."))?;
+    }
+    for stop in stops.iter() {
+        out.line(format_args!("{} offset {}", stop.key, stop.offset))?;
+        out.line(format_args!("{}", stop.plaintext))?;
+    }
+    out.close()
+}
+
+// Random daily keys drawn from the rotors, greek wheels and reflectors of the machine.
+fn main_keysheet(program: &str, args: &[String]) -> Result<(), Error> {
+    let args = parse_args(args, &[])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, "keysheet"));
+        return Ok(());
+    }
+    let mut days = 31;
+    let mut pairs = 10;
+    let mut seed = None;
+    let mut output_path = None;
+    let mut spec = cryptanalysis::SearchSpec::new();
+    for &(option, value) in args.options.iter() {
+        match option {
+            "--days" => match value.parse() {
+                Ok(n) if 0 < n => days = n,
+                _ => return Err(Error::Usage(format!("invalid number of days '{}'", value))),
+            },
+            "--pairs" => match value.parse() {
+                Ok(n) if n <= 13 => pairs = n,
+                _ => return Err(Error::Usage(format!("invalid number of pairs '{}', use 0 to 13", value))),
+            },
+            "--seed" => match value.parse() {
+                Ok(n) => seed = Some(n),
+                Err(_) => return Err(Error::Usage(format!("invalid seed '{}'", value))),
+            },
+            "--output" => output_path = Some(value),
+            "--machine" | "--pool" | "--greek" | "--reflector" => parse_search_option(&mut spec, option, value)?,
+            otherwise => return Err(Error::Usage(format!("unrecognized option {}", otherwise))),
+        }
+    }
+    if !args.positional.is_empty() {
+        return Err(usage_error(program, "keysheet", "keysheet only takes options"));
+    }
+    if spec.rotor_pool.len() < 3 || spec.reflectors.is_empty() {
+        return Err(Error::Usage("the pool should have 3 rotors or more and there should be a reflector".to_string()));
+    }
+    let seed = seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0));
+    let mut rng = cryptanalysis::Rng::new(seed);
+    let mut out = Output::open(output_path)?;
+    for day in 1..days + 1 {
+        let key = spec.random_key(pairs, &mut rng)?;
+        out.line(format_args!("Day {:2} {}", day, key))?;
+    }
+    out.close()
+}
+
+// Scores a text, e.g. a decryption with a guessed key, the way the searches do.
+fn main_score(program: &str, args: &[String]) -> Result<(), Error> {
+    let args = parse_args(args, &[])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, "score"));
+        return Ok(());
+    }
+    let mut model_options = ModelOptions::new();
+    let mut input_path = None;
+    let mut output_path = None;
+    for &(option, value) in args.options.iter() {
+        match option {
+            "--input" => input_path = Some(value),
+            "--output" => output_path = Some(value),
+            _ if parse_model_option(&mut model_options, option, value)? => (),
+            otherwise => return Err(Error::Usage(format!("unrecognized option {}", otherwise))),
+        }
+    }
+    if !args.positional.is_empty() {
+        return Err(usage_error(program, "score", "score reads the text from the input"));
+    }
+    let model = model_options.load()?;
+    let text = encrypt::input_to_u8(&read_input(input_path)?);
+    let score = model.score(&text);
+    let ngrams = (text.len() + 1).saturating_sub(model.n());
+    let confidence = cryptanalysis::text_confidence(&model, &text);
+    let mut out = Output::open(output_path)?;
+    out.line(format_args!("{} letters, score {:.2}, {:.3} per {}-gram", text.len(), score,
+                          if ngrams == 0 { 0. } else { score / ngrams as f64 }, model.n()))?;
+    out.line(format_args!("z-score {:.1} IC {:.4}", confidence.z_score, confidence.ic))?;
+    out.line(format_args!("{}", if confidence.broken { "Plaintext" } else { "Random: the text scores like random letters." }))?;
+    out.close()
+}
+
+static NGRAM_NAMES : [&str; 5] = [
+    "monograms", "bigrams", "trigrams", "quadgrams", "quintgrams",
+];
+
+fn main_train(program: &str, args: &[String]) -> Result<(), Error> {
+    let args = parse_args(args, &["--umlauts", "--x-for-space"])?;
+    if args.flag("--help") {
+        println!("{}", usage(program, "train"));
+        return Ok(());
+    }
+    let mut corpus_path = None;
+    let mut prefix = None;
+    for &(option, value) in args.options.iter() {
+        match option {
+            "--input" => corpus_path = Some(value),
+            "--output" => prefix = Some(value),
+            otherwise => return Err(Error::Usage(format!("unrecognized option {}", otherwise))),
+        }
+    }
+    let mut positional = args.positional.iter();
+    let corpus_path = corpus_path.or_else(|| positional.next().copied());
+    let prefix = prefix.or_else(|| positional.next().copied());
+    let (corpus_path, prefix) = match (corpus_path, prefix, positional.next()) {
+        (Some(corpus_path), Some(prefix), None) => (corpus_path, prefix),
+        _ => return Err(usage_error(program, "train", "train takes a corpus and a prefix")),
+    };
+    let corpus = read_input(Some(corpus_path))?;
+    let text = ngram::normalise(&corpus, args.flag("--umlauts"), args.flag("--x-for-space"));
+    for n in 1..NGRAM_NAMES.len() + 1 {
+        let path = format!("{}_{}.txt", prefix, NGRAM_NAMES[n - 1]);
+        let counts = ngram::counts_to_string(&ngram::count(&text, n), n);
+        if let Err(err) = File::create(&path).and_then(|mut file| file.write_all(counts.as_bytes())) {
+            return Err(Error::Io { path, message: err.to_string() });
+        }
+        println!("Wrote {}", path);
+    }
+    Ok(())
+}
+
+// Errors are printed on stderr, the exit code telling invalid arguments, unreadable files
+// and invalid keys or messages apart.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].as_str();
+    let res = match args.get(1).map(|command| command.as_str()) {
+        None => {
+            let _ = writeln!(io::stderr(), "{}", usage(program, ""));
+            Err(Error::Usage("missing command".to_string()))
+        },
+        Some("help") | Some("-h") | Some("--help") => match args.get(2) {
+            Some(command) => { println!("{}", usage(program, command)); Ok(()) },
+            None => { println!("{}", usage(program, "")); Ok(()) },
+        },
+        Some(command) => {
+            let rest = &args[2..];
+            match command {
+                "encrypt" | "decrypt" => main_encrypt(program, command, rest),
+                "interactive" => main_interactive(program, rest),
+                "break" | "merge" | "depth" | "indicators" => main_break(program, command, rest),
+                "bombe" => main_bombe(program, rest),
+                "keysheet" => main_keysheet(program, rest),
+                "score" => main_score(program, rest),
+                "train" => main_train(program, rest),
+                otherwise => Err(Error::Usage(format!("unrecognized command {}, use '{} help' for the list of commands", otherwise, program))),
+            }
+        },
+    };
+    if let Err(err) = res {
+        let _ = writeln!(io::stderr(), "Error: {}", err);
+        process::exit(err.exit_code());
+    }
+}
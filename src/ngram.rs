@@ -0,0 +1,330 @@
+use std::fs::File;
+use std::io::Read;
+use crate::encrypt;
+use crate::encrypt::LANES;
+use crate::error::Error;
+
+// The english quadgram counts are adapted from
+//   http://www.practicalcryptography.com/cryptanalysis/breaking-machine-ciphers/cryptanalysis-enigma/
+//   author: James Lyons Aug 2012
+// The bigram counts are obtained by summing the quadgram counts over their first two letters.
+static ENGLISH_QUADGRAMS : &str = include_str!("../data/english_quadgrams.txt");
+static ENGLISH_BIGRAMS : &str = include_str!("../data/english_bigrams.txt");
+
+// The german counts are generated from data/german_corpus.txt using:
+//   enigma train data/german_corpus.txt data/german --umlauts --x-for-space
+// the words being separated by X as in real traffic. That corpus is a short text written
+// for this crate, about 15,000 letters, so most quadgrams are missing from it and the
+// model is much weaker than the english one. Counts trained in the same way on a large
+// public domain corpus, e.g. german books of Project Gutenberg, can be used with --ngrams.
+static GERMAN_QUADGRAMS : &str = include_str!("../data/german_quadgrams.txt");
+static GERMAN_BIGRAMS : &str = include_str!("../data/german_bigrams.txt");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    English,
+    German,
+}
+
+impl Language {
+    pub fn from_name(name: &str) -> Option<Language> {
+        match name.to_lowercase().as_str() {
+            "english" | "en" => Some(Language::English),
+            "german" | "de" => Some(Language::German),
+            _ => None,
+        }
+    }
+
+    // The models shipped with the crate, only bigrams and quadgrams are available.
+    pub fn ngrams(&self, n: usize) -> Option<NGramModel> {
+        let counts = match (*self, n) {
+            (Language::English, 2) => ENGLISH_BIGRAMS,
+            (Language::English, 4) => ENGLISH_QUADGRAMS,
+            (Language::German, 2) => GERMAN_BIGRAMS,
+            (Language::German, 4) => GERMAN_QUADGRAMS,
+            _ => return None,
+        };
+        Some(NGramModel::from_counts(counts, None).unwrap())
+    }
+}
+
+// Log-probabilities for all the n-grams over A-Z, indexed in base 26 with the
+// first letter being the most significant digit. They are stored as fixed point numbers,
+// log_probs[i] / scale, so that the table of the quadgrams fits in the L2 cache with the
+// scramblers of encrypt, the lookups in it being most of the time taken by the searches.
+pub struct NGramModel {
+    n: usize,
+    log_probs: Vec<i16>,
+    scale: f64,
+    // Mean and variance of random_distribution, computed once as the ring search uses it
+    // for every candidate.
+    random: (f64, f64),
+}
+
+// The longest n-grams supported, the tables having 26^n entries.
+pub const MAX_N: usize = 6;
+
+// The floor used when no explicit value is given puts unseen n-grams 3 below the rarest
+// ones, i.e. gives them a count of 0.001. This is the gap of the quadgram table of
+// practicalcryptography that the searches were tuned with, -9.5 against -6.49.
+fn default_floor(total: f64) -> f64 {
+    (0.001 / total).log10()
+}
+
+impl NGramModel {
+    // Parses the usual "TION 13168375" format, one n-gram per line. All the n-grams
+    // must have the same length.
+    pub fn from_counts(input: &str, floor: Option<f64>) -> Result<NGramModel, Error> {
+        let mut n = 0;
+        let mut counts = Vec::new();
+        for (line_idx, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() { continue; }
+            let mut fields = line.split_whitespace();
+            let (ngram, count) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(ngram), Some(count), None) => (ngram, count),
+                _ => return Err(Error::NGrams(format!("line {}: expected 'NGRAM COUNT', got '{}'", line_idx + 1, line))),
+            };
+            let ngram = encrypt::input_to_u8(ngram);
+            if ngram.is_empty() || MAX_N < ngram.len() {
+                return Err(Error::NGrams(format!("line {}: n-grams should have 1 to {} letters, got '{}'", line_idx + 1, MAX_N, line)));
+            }
+            if n == 0 { n = ngram.len(); }
+            if ngram.len() != n {
+                return Err(Error::NGrams(format!("line {}: n-gram '{}' has a length different from {}", line_idx + 1, line, n)));
+            }
+            let count: f64 = match count.parse() {
+                Ok(count) if 0. < count && f64::is_finite(count) => count,
+                _ => return Err(Error::NGrams(format!("line {}: invalid count '{}'", line_idx + 1, count))),
+            };
+            counts.push((ngram, count));
+        }
+        if n == 0 {
+            return Err(Error::NGrams("no n-gram found".to_string()));
+        }
+        if floor.is_some_and(|floor| !floor.is_finite()) {
+            return Err(Error::NGrams(format!("the floor should be a number, not {}", floor.unwrap())));
+        }
+        let total: f64 = counts.iter().map(|&(_, count)| count).sum();
+        let floor = floor.unwrap_or(default_floor(total));
+        // The scale is the largest one keeping all the values within an i16, with a
+        // resolution of 0.001 or better for the usual floors.
+        let largest = counts.iter().map(|&(_, count)| (count / total).log10().abs()).fold(floor.abs(), f64::max);
+        let scale = (i16::MAX as f64 / largest).min(1e6);
+        let quantise = |log_prob: f64| (log_prob * scale).round() as i16;
+        let mut log_probs = vec![quantise(floor); 26usize.pow(n as u32)];
+        for &(ref ngram, count) in counts.iter() {
+            let index = ngram.iter().fold(0, |acc, &c| acc * 26 + c as usize);
+            log_probs[index] = quantise((count / total).log10());
+        }
+        let len = log_probs.len() as f64;
+        let mean = log_probs.iter().map(|&p| p as f64 / scale).sum::<f64>() / len;
+        let variance = log_probs.iter().map(|&p| (p as f64 / scale - mean).powi(2)).sum::<f64>() / len;
+        Ok(NGramModel { n, log_probs, scale, random: (mean, variance) })
+    }
+
+    pub fn from_file(path: &str, floor: Option<f64>) -> Result<NGramModel, Error> {
+        let mut input = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut input)) {
+            Ok(_) => NGramModel::from_counts(&input, floor),
+            Err(err) => Err(Error::Io { path: path.to_string(), message: err.to_string() }),
+        }
+    }
+
+    pub fn n(&self) -> usize { self.n }
+
+    // Identifies the model, e.g. to check that a search is resumed with the same one.
+    pub fn fingerprint(&self) -> u64 {
+        let hash = fnv1a(0, &[self.n as u8]);
+        self.log_probs.iter().fold(fnv1a(hash, &self.scale.to_bits().to_le_bytes()), |hash, p| fnv1a(hash, &p.to_le_bytes()))
+    }
+
+    // Mean and variance of the log-probability of an n-gram of uniformly random letters,
+    // which is what a decryption with a wrong key looks like.
+    pub fn random_distribution(&self) -> (f64, f64) {
+        self.random
+    }
+
+    // Sum of the log-probabilities of all the n-grams appearing in text.
+    pub fn score(&self, text: &[u8]) -> f64 {
+        self.score_iter(text.iter().copied())
+    }
+
+    // The log-probability of the n-gram with the given index, as used by the scores.
+    pub fn log_prob(&self, index: usize) -> f64 {
+        self.log_probs[index] as f64 / self.scale
+    }
+
+    // Same as score, this can be used to score a decryption without collecting it.
+    // The scores are summed as integers and only converted at the end.
+    pub fn score_iter<I: Iterator<Item=u8>>(&self, text: I) -> f64 {
+        let weight = (self.log_probs.len() / 26) as u32;
+        let mut score : i64 = 0;
+        let mut index : u32 = 0;
+        for (idx, c) in text.enumerate() {
+            if 25 < c { continue; }
+            index = (index % weight) * 26 + c as u32;
+            if self.n <= idx + 1 {
+                score += self.log_probs[index as usize] as i64;
+            }
+        }
+        score as f64 / self.scale
+    }
+
+    // The log-probabilities of the successive n-grams of text, to tell which parts of a
+    // decryption read well.
+    pub fn ngram_scores(&self, text: &[u8]) -> Vec<f64> {
+        let weight = self.log_probs.len() / 26;
+        let mut index = 0;
+        text.iter().enumerate().filter_map(|(idx, &c)| {
+            index = (index % weight) * 26 + c as usize;
+            if self.n <= idx + 1 { Some(self.log_prob(index)) } else { None }
+        }).collect()
+    }
+
+    // Scores LANES texts given letter by letter in lock-step, the result for each lane
+    // being the same as the one of score_iter.
+    pub fn score_lanes<I: Iterator<Item=[u8; LANES]>>(&self, text: I) -> [f64; LANES] {
+        let weight = (self.log_probs.len() / 26) as u32;
+        let mut score = [0i64; LANES];
+        let mut index = [0u32; LANES];
+        for (idx, letters) in text.enumerate() {
+            for lane in 0..LANES {
+                index[lane] = (index[lane] % weight) * 26 + letters[lane] as u32;
+            }
+            if self.n <= idx + 1 {
+                for lane in 0..LANES {
+                    score[lane] += self.log_probs[index[lane] as usize] as i64;
+                }
+            }
+        }
+        score.map(|score| score as f64 / self.scale)
+    }
+}
+
+// FNV-1a hash of bytes, starting from hash or from the usual offset basis when hash is 0.
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    let hash = if hash == 0 { 0xcbf29ce484222325 } else { hash };
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// Same filtering as encrypt::input_to_u8, optionally expanding umlauts and eszett
+// (Ä -> AE, ß -> SS) and replacing each run of whitespace with a single X as was
+// customary in German traffic.
+pub fn normalise(input: &str, expand_umlauts: bool, x_for_space: bool) -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut pending_space = false;
+    for c in input.chars() {
+        if c.is_whitespace() {
+            pending_space = x_for_space && !res.is_empty();
+            continue;
+        }
+        let expanded = match c {
+            'ä' | 'Ä' if expand_umlauts => "AE",
+            'ö' | 'Ö' if expand_umlauts => "OE",
+            'ü' | 'Ü' if expand_umlauts => "UE",
+            'ß' | 'ẞ' if expand_umlauts => "SS",
+            _ => "",
+        };
+        let letters: Vec<u8> =
+            if expanded.is_empty() { encrypt::ord(c).into_iter().collect() }
+            else { encrypt::input_to_u8(expanded) };
+        if letters.is_empty() { continue; }
+        if pending_space { res.push(23u8); }
+        pending_space = false;
+        res.extend(letters);
+    }
+    res
+}
+
+// Counts of all the n-grams of text, indexed in the same way as NGramModel, n being at
+// most MAX_N.
+pub fn count(text: &[u8], n: usize) -> Vec<u64> {
+    let mut counts = vec![0u64; 26usize.pow(n as u32)];
+    let modulo = counts.len() / 26;
+    let mut index : usize = 0;
+    for (idx, &c) in text.iter().enumerate() {
+        index = (index % modulo) * 26 + c as usize;
+        if n <= idx + 1 {
+            counts[index] += 1;
+        }
+    }
+    counts
+}
+
+// Formats counts as returned by count() in the format read by NGramModel::from_counts,
+// most frequent n-grams first.
+pub fn counts_to_string(counts: &[u64], n: usize) -> String {
+    let mut sorted: Vec<(u64, usize)> =
+        counts.iter().enumerate().filter(|&(_, &c)| 0 < c).map(|(index, &c)| (c, index)).collect();
+    sorted.sort_by(|&(c1, i1), &(c2, i2)| c2.cmp(&c1).then(i1.cmp(&i2)));
+    let mut res = String::new();
+    for &(c, index) in sorted.iter() {
+        let ngram: String = (0..n).rev().map(|k| encrypt::chr(((index / 26usize.pow(k as u32)) % 26) as u8)).collect();
+        res.push_str(&format!("{} {}\n", ngram, c));
+    }
+    res
+}
+
+#[test]
+fn ngram_tests() {
+    let model = NGramModel::from_counts("AB 3\nBA 1\n", Some(-10.)).unwrap();
+    assert_eq!(model.n(), 2);
+    let expected = 2. * 0.75f64.log10() + 0.25f64.log10() - 10.;
+    // The log-probabilities are rounded to 1 / scale, about 3e-4 here.
+    assert!((model.score(&encrypt::input_to_u8("ABABZ")) - expected).abs() < 1e-3);
+    let (mean, variance) = model.random_distribution();
+    assert!((mean - (0.75f64.log10() + 0.25f64.log10() - 6740.) / 676.).abs() < 1e-3);
+    assert!(0. < variance);
+    assert!(NGramModel::from_counts("AB 3\nABC 1\n", None).is_err());
+    assert!(NGramModel::from_counts("AB x\n", None).is_err());
+    // N-grams without letters, too long or with counts that are not positive, and floors
+    // that are not numbers are rejected rather than corrupting the table.
+    for invalid in ["AB 3\n12 1\n", "ABCDEFG 1\n", "AB nan\n", "AB 0\n", "AB inf\n"].iter() {
+        assert!(matches!(NGramModel::from_counts(invalid, None), Err(Error::NGrams(_))), "{}", invalid);
+    }
+    assert!(NGramModel::from_counts("AB 3\n", Some(f64::NAN)).is_err());
+    assert!(NGramModel::from_counts("AB 3\n", Some(f64::NEG_INFINITY)).is_err());
+    let model = NGramModel::from_counts("AB 3\nBA 1\n", None).unwrap();
+    assert!((model.score(&encrypt::input_to_u8("AA")) - (0.25f64.log10() - 3.)).abs() < 1e-3);
+    assert_eq!(model.score(&encrypt::input_to_u8("AA")), model.log_prob(0));
+    // The scale keeps very low floors within the fixed point range.
+    let model = NGramModel::from_counts("AB 3\nBA 1\n", Some(-500.)).unwrap();
+    assert!((model.log_prob(0) + 500.).abs() < 0.1 && (model.log_prob(1) - 0.75f64.log10()).abs() < 0.1);
+    for &language in [Language::English, Language::German].iter() {
+        assert_eq!(language.ngrams(2).unwrap().n(), 2);
+        assert_eq!(language.ngrams(4).unwrap().n(), 4);
+        assert!(language.ngrams(3).is_none());
+    }
+    let german = Language::German.ngrams(4).unwrap();
+    let english = Language::English.ngrams(4).unwrap();
+    let text = normalise("Die Walzen werden täglich gewechselt", true, true);
+    assert!(english.score(&text) < german.score(&text));
+}
+
+#[test]
+fn score_lanes_tests() {
+    let model = Language::English.ngrams(4).unwrap();
+    let texts: Vec<Vec<u8>> = (0..LANES).map(|lane|
+        (0..50).map(|idx| ((idx * 7 + lane * lane + idx * idx * lane) % 26) as u8).collect()).collect();
+    let scores = model.score_lanes((0..50).map(|idx| {
+        let mut letters = [0u8; LANES];
+        for lane in 0..LANES { letters[lane] = texts[lane][idx]; }
+        letters
+    }));
+    for lane in 0..LANES {
+        assert_eq!(scores[lane], model.score(&texts[lane]));
+    }
+}
+
+#[test]
+fn train_tests() {
+    let text = normalise("Grüße  aus\nKöln, 42!", true, true);
+    assert_eq!(text, encrypt::input_to_u8("GRUESSEXAUSXKOELN"));
+    assert_eq!(normalise("Grüße aus", false, false), encrypt::input_to_u8("GREAUS"));
+    let text = encrypt::input_to_u8("ABABA");
+    let counts = count(&text, 2);
+    assert_eq!(counts_to_string(&counts, 2), "AB 2\nBA 2\n");
+    let model = NGramModel::from_counts(&counts_to_string(&count(&text, 3), 3), None).unwrap();
+    assert_eq!(model.n(), 3);
+}